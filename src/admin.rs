@@ -0,0 +1,357 @@
+use crate::config::ContposeConfig;
+use crate::events::{self, Event, EventKind};
+use crate::instance::{ContainerStatus, InstanceStatus, Instances};
+use crate::master::MasterMsg;
+use crate::plan::{self, PlannedChange};
+use crate::signals;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::Shutdown,
+    os::{
+        fd::OwnedFd,
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Events {
+        recent: Option<usize>,
+    },
+    Status,
+    Ps,
+    /// Check every image right away.
+    Poll,
+    /// Shut down as configured by `on_shutdown` and exit.
+    Stop,
+    Trigger {
+        instance: PathBuf,
+    },
+    Restart {
+        instance: PathBuf,
+    },
+    Deploy {
+        instance: PathBuf,
+        pull: bool,
+    },
+    Pending,
+    Approve {
+        instance: PathBuf,
+    },
+    /// Read the config again, or only report what
+    /// doing so would change.
+    Reload {
+        dry_run: bool,
+    },
+    Logs {
+        instance: PathBuf,
+        follow: bool,
+        since: Option<String>,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Events {
+        events: Vec<Event>,
+    },
+    Status {
+        instances: Vec<InstanceStatus>,
+    },
+    Ps {
+        containers: Vec<ContainerStatus>,
+        /// Instances whose containers couldn't be listed.
+        errors: Vec<String>,
+    },
+    Pending {
+        instances: Vec<PathBuf>,
+    },
+    Plan {
+        changes: Vec<PlannedChange>,
+    },
+    Done {
+        message: String,
+    },
+    /// Raw output follows this response until the connection closes.
+    Streaming,
+    Error {
+        message: String,
+    },
+}
+
+/// Listens on a unix socket for requests from the
+/// `dispenser` CLI while the daemon is running.
+pub fn serve(socket: &Path, instances: Arc<Mutex<Instances>>) {
+    // A socket left behind by a previous run would make bind fail
+    let _ = std::fs::remove_file(socket);
+    let listener = match UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to bind admin socket at {socket:?}: {e}");
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Unable to accept admin connection: {e}");
+                    continue;
+                }
+            };
+            // Each client gets its own thread since
+            // following logs can take forever
+            let instances = Arc::clone(&instances);
+            std::thread::spawn(move || {
+                if let Err(e) = handle_client(stream, &instances) {
+                    log::warn!("Admin request failed: {e}");
+                }
+            });
+        }
+    });
+}
+
+fn handle_client(
+    stream: UnixStream,
+    instances: &Mutex<Instances>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(AdminRequest::Logs {
+            instance,
+            follow,
+            since,
+        }) => return stream_logs(stream, instances, &instance, follow, since.as_deref()),
+        Ok(AdminRequest::Stop) => {
+            log::warn!("Received stop request from the admin socket");
+            let response = AdminResponse::Done {
+                message: "Stopping dispenser".to_string(),
+            };
+            serde_json::to_writer(&stream, &response)?;
+            drop(stream);
+            signals::shutdown(instances)
+        }
+        Ok(request) => handle_request(request, instances),
+        Err(e) => AdminResponse::Error {
+            message: format!("Invalid request: {e}"),
+        },
+    };
+    serde_json::to_writer(&stream, &response)?;
+    Ok(())
+}
+
+fn handle_request(request: AdminRequest, instances: &Mutex<Instances>) -> AdminResponse {
+    match request {
+        AdminRequest::Logs { .. } => unreachable!("Logs are streamed by handle_client"),
+        AdminRequest::Stop => unreachable!("Stop is handled by handle_client"),
+        AdminRequest::Events { recent } => AdminResponse::Events {
+            events: events::recent(recent),
+        },
+        AdminRequest::Status => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            AdminResponse::Status {
+                instances: instances.inner.iter().map(|inst| inst.status()).collect(),
+            }
+        }
+        AdminRequest::Ps => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            let mut containers = Vec::new();
+            let mut errors = Vec::new();
+            for instance in &instances.inner {
+                match instance.containers() {
+                    Ok(found) => containers.extend(found),
+                    Err(e) => errors.push(format!(
+                        "Unable to list containers of {:?}: {e}",
+                        instance.config.path
+                    )),
+                }
+            }
+            AdminResponse::Ps { containers, errors }
+        }
+        AdminRequest::Poll => {
+            log::info!("Polling right away as requested from the admin socket");
+            signals::request_poll();
+            AdminResponse::Done {
+                message: "Polling every image now".to_string(),
+            }
+        }
+        AdminRequest::Trigger { instance } => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            match instances.find(&instance) {
+                Some(found) => {
+                    log::info!("Manually triggered a deploy of {instance:?}");
+                    events::record(EventKind::Trigger, format!("Manual deploy of {instance:?}"));
+                    found.deploy();
+                    AdminResponse::Done {
+                        message: format!("Triggered a deploy of {instance:?}"),
+                    }
+                }
+                None => unknown_instance(&instance),
+            }
+        }
+        AdminRequest::Restart { instance } => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            match instances.find(&instance) {
+                Some(found) => {
+                    log::info!("Manually restarting {instance:?}");
+                    events::record(
+                        EventKind::Trigger,
+                        format!("Manual restart of {instance:?}"),
+                    );
+                    found.master.send_msg(MasterMsg::Restart {
+                        timeout: instances.shutdown_timeout,
+                    });
+                    AdminResponse::Done {
+                        message: format!("Restarting {instance:?}"),
+                    }
+                }
+                None => unknown_instance(&instance),
+            }
+        }
+        AdminRequest::Deploy { instance, pull } => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            match instances.find(&instance) {
+                Some(found) => {
+                    log::info!("Manually forcing a deploy of {instance:?}");
+                    events::record(EventKind::Trigger, format!("Forced deploy of {instance:?}"));
+                    found.force_deploy(pull);
+                    AdminResponse::Done {
+                        message: format!("Recreating the services of {instance:?}"),
+                    }
+                }
+                None => unknown_instance(&instance),
+            }
+        }
+        AdminRequest::Pending => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            AdminResponse::Pending {
+                instances: instances
+                    .inner
+                    .iter()
+                    .filter(|inst| inst.has_pending_update())
+                    .map(|inst| inst.config.path.clone())
+                    .collect(),
+            }
+        }
+        AdminRequest::Approve { instance } => {
+            let instances = instances.lock().expect("Unable to lock").clone();
+            match instances.find(&instance) {
+                Some(found) => match found.approve() {
+                    Ok(()) => {
+                        log::info!("Approved the pending update of {instance:?}");
+                        events::record(
+                            EventKind::Trigger,
+                            format!("Approved update of {instance:?}"),
+                        );
+                        AdminResponse::Done {
+                            message: format!("Deploying the update of {instance:?}"),
+                        }
+                    }
+                    Err(message) => AdminResponse::Error { message },
+                },
+                None => unknown_instance(&instance),
+            }
+        }
+        AdminRequest::Reload { dry_run: true } => match ContposeConfig::try_init() {
+            Ok(new_config) => {
+                let instances = instances.lock().expect("Unable to lock").clone();
+                AdminResponse::Plan {
+                    changes: plan::plan(&instances, &new_config),
+                }
+            }
+            Err(e) => AdminResponse::Error {
+                message: format!("Unable to read new config: {e}"),
+            },
+        },
+        AdminRequest::Reload { dry_run: false } => match signals::reload(instances) {
+            Ok(()) => AdminResponse::Done {
+                message: "Reloaded the configuration".to_string(),
+            },
+            Err(message) => AdminResponse::Error { message },
+        },
+    }
+}
+
+/// Hands the socket to `docker compose logs` so the
+/// output goes straight to the client.
+fn stream_logs(
+    stream: UnixStream,
+    instances: &Mutex<Instances>,
+    instance: &Path,
+    follow: bool,
+    since: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let instances = instances.lock().expect("Unable to lock").clone();
+    let Some(found) = instances.find(instance) else {
+        serde_json::to_writer(&stream, &unknown_instance(instance))?;
+        return Ok(());
+    };
+    let mut command = found.master.compose_command();
+    command.args(["logs", "--no-color"]);
+    if follow {
+        command.arg("--follow");
+    }
+    if let Some(since) = since {
+        command.args(["--since", since]);
+    }
+
+    serde_json::to_writer(&stream, &AdminResponse::Streaming)?;
+    (&stream).write_all(b"\n")?;
+    let stderr = OwnedFd::from(stream.try_clone()?);
+    command
+        .stdout(OwnedFd::from(stream))
+        .stderr(stderr)
+        .status()?;
+    Ok(())
+}
+
+fn unknown_instance(instance: &Path) -> AdminResponse {
+    AdminResponse::Error {
+        message: format!("There is no instance at {instance:?}"),
+    }
+}
+
+fn connect(
+    socket: &Path,
+    request: &AdminRequest,
+) -> Result<UnixStream, Box<dyn std::error::Error>> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format!("Unable to connect to dispenser at {socket:?}: {e}"))?;
+    serde_json::to_writer(&stream, request)?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(stream)
+}
+
+/// Sends a single request to a running daemon and
+/// waits for its response.
+pub fn send(
+    socket: &Path,
+    request: &AdminRequest,
+) -> Result<AdminResponse, Box<dyn std::error::Error>> {
+    let stream = connect(socket, request)?;
+    Ok(serde_json::from_reader(&stream)?)
+}
+
+/// Sends a request whose answer is a stream of raw
+/// output, returning a reader positioned at its start.
+pub fn stream(
+    socket: &Path,
+    request: &AdminRequest,
+) -> Result<BufReader<UnixStream>, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(connect(socket, request)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        AdminResponse::Streaming => Ok(reader),
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
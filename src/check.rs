@@ -0,0 +1,212 @@
+use crate::config::ContposeConfig;
+use crate::master;
+use crate::process;
+use crate::registry;
+use crate::rootless;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// A published port: docker endpoint, protocol and number.
+type PortKey = (Option<String>, String, u16);
+
+/// Looks past whether the config parses: credentials, images,
+/// and the compose files of every instance. Returns every
+/// problem found rather than stopping at the first one.
+pub fn check(config: &ContposeConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // Logged in like the daemon does, so images are resolved
+    // with the configured credentials
+    for registry in &config.registry {
+        if let Err(e) = registry::try_login(registry) {
+            problems.push(format!("Registry {:?}: {e}", registry.name));
+        }
+    }
+    if let Some(Err(e)) = config.webhook.as_ref().map(|webhook| webhook.get_secret()) {
+        problems.push(format!("Webhook: {e}"));
+    }
+
+    // Host IPs and services publishing each port of each docker
+    // endpoint, to catch two services fighting over one
+    let mut ports: HashMap<PortKey, Vec<(String, String)>> = HashMap::new();
+    let lowest_port = rootless::is_rootless().then(rootless::unprivileged_port_start);
+
+    for instance in &config.instance {
+        let path = &instance.path;
+        let endpoint = config.get_endpoint(instance);
+
+        let mut images = instance.clone();
+        images.apply_mirrors(&config.mirrors);
+        for image in images.images() {
+            if let Err(e) = resolve(&image.to_string(), config) {
+                problems.push(format!(
+                    "Instance {path:?}: image {image} can't be resolved: {e}"
+                ));
+            }
+        }
+
+        let project =
+            match master::compose_config(path, endpoint.as_deref(), config.pull.get_timeout()) {
+                Ok(project) => project,
+                Err(e) => {
+                    problems.push(format!("Instance {path:?}: invalid compose project: {e}"));
+                    continue;
+                }
+            };
+        let declared_networks = project.get("networks").and_then(Value::as_object);
+        let services = project
+            .get("services")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten();
+        for (name, service) in services {
+            let service_ref = format!("{}/{name}", path.display());
+
+            let published = service
+                .get("ports")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten();
+            for port in published {
+                let Some(published) = port.get("published").and_then(as_ports) else {
+                    continue;
+                };
+                let host_ip = port.get("host_ip").and_then(Value::as_str).unwrap_or("");
+                let protocol = port
+                    .get("protocol")
+                    .and_then(Value::as_str)
+                    .unwrap_or("tcp");
+                if let (None, Some(lowest)) = (&endpoint, lowest_port) {
+                    if *published.start() < lowest {
+                        problems.push(format!(
+                            "Service {service_ref} publishes port {}, rootless docker can only publish ports from {lowest}",
+                            published.start()
+                        ));
+                    }
+                }
+                // Only the first clash of a range is reported
+                for published in published {
+                    let taken = ports
+                        .entry((endpoint.clone(), protocol.to_string(), published))
+                        .or_default();
+                    // A service may publish a port on both 0.0.0.0 and ::
+                    let clash = taken.iter().find(|(other_ip, other)| {
+                        *other != service_ref && same_address(host_ip, other_ip)
+                    });
+                    if let Some((_, other)) = clash {
+                        problems.push(format!(
+                            "Port {published}/{protocol} is published by both {other} and {service_ref}"
+                        ));
+                        break;
+                    }
+                    taken.push((host_ip.to_string(), service_ref.clone()));
+                }
+            }
+
+            // Bind mounts on remote hosts can't be checked from here
+            if endpoint.is_none() {
+                let volumes = service
+                    .get("volumes")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten();
+                for volume in volumes {
+                    if volume.get("type").and_then(Value::as_str) != Some("bind") {
+                        continue;
+                    }
+                    if let Some(source) = volume.get("source").and_then(Value::as_str) {
+                        if !Path::new(source).exists() {
+                            problems.push(format!(
+                                "Service {service_ref} mounts {source:?} which does not exist"
+                            ));
+                        }
+                    }
+                }
+            }
+
+            let networks = service
+                .get("networks")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten();
+            for (network, _) in networks {
+                if !declared_networks.is_some_and(|declared| declared.contains_key(network)) {
+                    problems.push(format!(
+                        "Service {service_ref} uses network {network:?} which is not declared"
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Compose has written published ports both as numbers and
+/// as strings over the years, the latter also as ranges
+/// like `8000-8010`.
+fn as_ports(value: &Value) -> Option<RangeInclusive<u16>> {
+    match value {
+        Value::Number(port) => {
+            let port = u16::try_from(port.as_u64()?).ok()?;
+            Some(port..=port)
+        }
+        Value::String(ports) => match ports.split_once('-') {
+            Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+            None => {
+                let port = ports.trim().parse().ok()?;
+                Some(port..=port)
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Whether two host IPs a port is published on overlap. A port
+/// published on every address clashes with any other one.
+fn same_address(a: &str, b: &str) -> bool {
+    let is_wildcard = |ip: &str| matches!(ip, "" | "0.0.0.0" | "::" | "[::]");
+    is_wildcard(a) || is_wildcard(b) || a == b
+}
+
+fn resolve(reference: &str, config: &ContposeConfig) -> Result<(), String> {
+    let output = process::output_with_timeout(
+        std::process::Command::new("docker")
+            .args(["manifest", "inspect"])
+            .arg(reference),
+        config.pull.get_timeout(),
+    )
+    .map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(process::last_line(&output.stderr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ports_and_ranges() {
+        assert_eq!(as_ports(&json!(8080)), Some(8080..=8080));
+        assert_eq!(as_ports(&json!("8080")), Some(8080..=8080));
+        assert_eq!(as_ports(&json!("8000-8010")), Some(8000..=8010));
+        assert_eq!(as_ports(&json!(70000)), None);
+        assert_eq!(as_ports(&json!("http")), None);
+        assert_eq!(as_ports(&json!(null)), None);
+    }
+
+    #[test]
+    fn wildcard_addresses_clash_with_any() {
+        for wildcard in ["", "0.0.0.0", "::", "[::]"] {
+            assert!(same_address(wildcard, "127.0.0.1"));
+            assert!(same_address("10.0.0.2", wildcard));
+        }
+        assert!(same_address("127.0.0.1", "127.0.0.1"));
+        assert!(!same_address("127.0.0.1", "10.0.0.2"));
+    }
+}
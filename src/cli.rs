@@ -1,14 +1,120 @@
 use std::{path::PathBuf, sync::OnceLock};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::graph::GraphFormat;
+
+/// Something to ask of a running daemon, like a unix signal.
+#[derive(clap::ValueEnum, Debug, Copy, Clone)]
+pub enum DaemonSignal {
+    /// Check every image now instead of after the delay.
+    Poll,
+    /// Read the config again.
+    Reload,
+    /// Shut down as configured by `on_shutdown` and exit.
+    Stop,
+}
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
 
 /// Continuous delivery for un-complicated infrastructure.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
     /// Path to the config file.
-    #[arg(short, long, default_value = "dispenser.toml")]
+    #[arg(short, long, global = true, default_value = "dispenser.toml")]
     pub config: PathBuf,
+    /// Path to the admin socket of the daemon.
+    #[arg(short, long, global = true, default_value = "dispenser.sock")]
+    pub socket: PathBuf,
+    /// Send a signal to the running daemon instead of starting one.
+    #[arg(long, value_enum)]
+    pub signal: Option<DaemonSignal>,
+    /// What to do, running the daemon if left out.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the daemon in the foreground.
+    Run,
+    /// Stop the running daemon, applying `on_shutdown` to its services.
+    Stop,
+    /// Show what the running daemon has been doing.
+    Events {
+        /// Only show the N most recent events.
+        #[arg(long, num_args = 0..=1, default_missing_value = "20")]
+        recent: Option<usize>,
+    },
+    /// Show the state of every instance.
+    Status,
+    /// List the containers of every instance.
+    Ps {
+        /// How to print the containers.
+        #[arg(short, long, value_enum, default_value = "table")]
+        output: OutputFormat,
+    },
+    /// Redeploy an instance right away.
+    Trigger {
+        /// Path of the instance as written in the config.
+        instance: PathBuf,
+    },
+    /// Recreate an instance's containers even if nothing changed.
+    Deploy {
+        /// Path of the instance as written in the config.
+        instance: PathBuf,
+        /// Pull the images again before recreating.
+        #[arg(long)]
+        pull: bool,
+    },
+    /// Take an instance's services down and up again.
+    Restart {
+        /// Path of the instance as written in the config.
+        instance: PathBuf,
+    },
+    /// Print the output of an instance's services.
+    Logs {
+        /// Path of the instance as written in the config.
+        instance: PathBuf,
+        /// Keep printing new output as it comes.
+        #[arg(short, long)]
+        follow: bool,
+        /// Only show output since a timestamp (e.g. 2024-01-01T10:00:00)
+        /// or relative time (e.g. 42m).
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// List instances with updates waiting for approval.
+    Pending,
+    /// Deploy the pending update of an instance.
+    Approve {
+        /// Path of the instance as written in the config.
+        instance: PathBuf,
+    },
+    /// Show what reloading the config would change.
+    Plan,
+    /// Read the config again.
+    Reload {
+        /// Only show what would change, like `dispenser plan`.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the config, its images and the compose
+    /// projects it points to without starting anything.
+    Test,
+    /// Print which images redeploy which instances.
+    Graph {
+        /// Output format of the graph.
+        #[arg(long, value_enum, default_value = "dot")]
+        format: GraphFormat,
+    },
+    /// Print a completion script for a shell.
+    Completions { shell: clap_complete::Shell },
 }
 
 static ARGS: OnceLock<Args> = OnceLock::new();
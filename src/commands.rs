@@ -0,0 +1,236 @@
+use crate::admin::{self, AdminRequest, AdminResponse};
+use crate::check;
+use crate::cli::{get_cli_args, Args, Command, DaemonSignal, OutputFormat};
+use crate::config::ContposeConfig;
+use crate::graph::{self, GraphFormat};
+use crate::instance::{ContainerStatus, InstanceStatus};
+use clap::CommandFactory;
+
+/// Runs a CLI subcommand instead of the daemon.
+pub fn run(command: &Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Run => unreachable!("The daemon is started by main"),
+        Command::Stop => done(AdminRequest::Stop),
+        Command::Events { recent } => events(*recent),
+        Command::Status => status(),
+        Command::Ps { output } => ps(*output),
+        Command::Trigger { instance } => done(AdminRequest::Trigger {
+            instance: instance.clone(),
+        }),
+        Command::Deploy { instance, pull } => done(AdminRequest::Deploy {
+            instance: instance.clone(),
+            pull: *pull,
+        }),
+        Command::Restart { instance } => done(AdminRequest::Restart {
+            instance: instance.clone(),
+        }),
+        Command::Logs {
+            instance,
+            follow,
+            since,
+        } => logs(AdminRequest::Logs {
+            instance: instance.clone(),
+            follow: *follow,
+            since: since.clone(),
+        }),
+        Command::Pending => pending(),
+        Command::Approve { instance } => done(AdminRequest::Approve {
+            instance: instance.clone(),
+        }),
+        Command::Plan => reload(true),
+        Command::Reload { dry_run } => reload(*dry_run),
+        Command::Test => test(),
+        Command::Graph { format } => graph(*format),
+        Command::Completions { shell } => {
+            clap_complete::generate(
+                *shell,
+                &mut Args::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Handles `--signal`, going through the admin socket
+/// so the daemon's pid doesn't need to be known.
+pub fn signal() -> Result<(), Box<dyn std::error::Error>> {
+    match get_cli_args().signal {
+        Some(DaemonSignal::Poll) => done(AdminRequest::Poll),
+        Some(DaemonSignal::Reload) => reload(false),
+        Some(DaemonSignal::Stop) => done(AdminRequest::Stop),
+        None => Ok(()),
+    }
+}
+
+fn events(recent: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &AdminRequest::Events { recent })? {
+        AdminResponse::Events { events } => {
+            for event in events {
+                println!("{event}");
+            }
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+fn status() -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &AdminRequest::Status)? {
+        AdminResponse::Status { instances } => {
+            for instance in instances {
+                print_status(&instance);
+            }
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+fn print_status(instance: &InstanceStatus) {
+    println!("{} ({})", instance.path.display(), instance.state);
+    println!(
+        "  host:        {}",
+        instance.host.as_deref().unwrap_or("local")
+    );
+    let last_deploy = instance
+        .last_deploy
+        .map(|time| humantime::format_rfc3339_seconds(time).to_string());
+    println!(
+        "  last deploy: {}",
+        last_deploy.as_deref().unwrap_or("never")
+    );
+    if instance.pending_update {
+        println!("  update waiting for approval");
+    }
+    for image in &instance.images {
+        println!(
+            "  {} (latest seen {})",
+            image.reference, image.latest_digest
+        );
+    }
+}
+
+fn ps(output: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &AdminRequest::Ps)? {
+        AdminResponse::Ps { containers, errors } => {
+            for error in errors {
+                eprintln!("{error}");
+            }
+            match output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&containers)?),
+                OutputFormat::Table => print_containers(&containers),
+            }
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+fn print_containers(containers: &[ContainerStatus]) {
+    let rows: Vec<[String; 7]> = containers
+        .iter()
+        .map(|c| {
+            [
+                c.instance.display().to_string(),
+                c.service.clone(),
+                c.state.clone(),
+                c.health.clone(),
+                c.status.clone(),
+                c.image.clone(),
+                c.ports.join(", "),
+            ]
+        })
+        .collect();
+    let header = [
+        "INSTANCE", "SERVICE", "STATE", "HEALTH", "STATUS", "IMAGE", "PORTS",
+    ]
+    .map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn reload(dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &AdminRequest::Reload { dry_run })? {
+        AdminResponse::Plan { changes } => {
+            for change in changes {
+                println!("{change}");
+            }
+            Ok(())
+        }
+        AdminResponse::Done { message } => {
+            println!("{message}");
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+/// Sends a request that only reports back whether it worked.
+fn done(request: AdminRequest) -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &request)? {
+        AdminResponse::Done { message } => {
+            println!("{message}");
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+fn logs(request: AdminRequest) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = admin::stream(&get_cli_args().socket, &request)?;
+    std::io::copy(&mut output, &mut std::io::stdout())?;
+    Ok(())
+}
+
+fn pending() -> Result<(), Box<dyn std::error::Error>> {
+    match admin::send(&get_cli_args().socket, &AdminRequest::Pending)? {
+        AdminResponse::Pending { instances } => {
+            for instance in instances {
+                println!("{}", instance.display());
+            }
+            Ok(())
+        }
+        AdminResponse::Error { message } => Err(message.into()),
+        _ => Err("Unexpected response from dispenser".into()),
+    }
+}
+
+fn test() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ContposeConfig::try_init()?;
+    let problems = check::check(&config);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    match problems.len() {
+        0 => {
+            println!("The config is valid");
+            Ok(())
+        }
+        count => Err(format!("Found {count} problem(s)").into()),
+    }
+}
+
+fn graph(format: GraphFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let config = ContposeConfig::try_init()?;
+    print!("{}", graph::render(&config, format));
+    Ok(())
+}
@@ -1,37 +1,272 @@
-use std::{num::NonZeroU64, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    instance::{Instance, Instances},
+    instance::{Instance, Instances, PreparedInstance, PreparedInstances},
     manifests::DockerWatcher,
+    plan::{Action, PlannedChange},
+    process::Pulls,
+    signature::Rejections,
 };
 
 #[derive(serde::Deserialize)]
 pub struct ContposeConfig {
     pub delay: NonZeroU64,
     #[serde(default)]
+    pub update_policy: UpdatePolicy,
+    /// Seconds containers get to exit on shutdown
+    /// before they are killed.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default)]
+    pub on_shutdown: OnShutdown,
+    /// Where to serve Prometheus metrics, if anywhere.
+    pub metrics: Option<SocketAddr>,
+    /// Where to listen for registry push webhooks, if anywhere.
+    pub webhook: Option<WebhookConfig>,
+    #[serde(default)]
+    pub pull: PullConfig,
+    #[serde(default)]
+    pub registry: Vec<RegistryConfig>,
+    /// Registries that should be reached through a mirror instead,
+    /// e.g. `"docker.io" = "mirror.internal:5000"`.
+    #[serde(default)]
+    pub mirrors: HashMap<String, String>,
+    #[serde(default)]
+    pub host: Vec<HostConfig>,
+    #[serde(default)]
     pub instance: Vec<ContposeInstanceConfig>,
 }
 
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+/// Credentials for a private registry.
+#[derive(serde::Deserialize, Clone)]
+pub struct RegistryConfig {
+    /// Registry host as used in the images, e.g. `ghcr.io`.
+    pub name: String,
+    pub username: String,
+    password: Option<String>,
+    /// File holding the password or token, read on every (re)load.
+    password_file: Option<PathBuf>,
+}
+
+impl RegistryConfig {
+    pub fn get_password(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match (&self.password, &self.password_file) {
+            (Some(password), None) => Ok(password.clone()),
+            (None, Some(file)) => std::fs::read_to_string(file)
+                .map_err(|e| format!("Unable to read {file:?}: {e}").into()),
+            _ => Err(format!(
+                "Registry {:?} needs exactly one of password or password_file",
+                self.name
+            )
+            .into()),
+        }
+    }
+}
+
+/// An HTTP endpoint registries notify of pushes.
+#[derive(serde::Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub address: SocketAddr,
+    secret: Option<String>,
+    /// File holding the shared secret, read when dispenser starts.
+    secret_file: Option<PathBuf>,
+}
+
+impl WebhookConfig {
+    pub fn get_secret(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let secret = match (&self.secret, &self.secret_file) {
+            (Some(secret), None) => secret.clone(),
+            (None, Some(file)) => std::fs::read_to_string(file)
+                .map_err(|e| format!("Unable to read {file:?}: {e}"))?
+                .trim()
+                .to_string(),
+            _ => return Err("The webhook needs exactly one of secret or secret_file".into()),
+        };
+        if secret.is_empty() {
+            return Err("The webhook secret is empty".into());
+        }
+        Ok(secret)
+    }
+}
+
+/// A docker daemon other than the local one that
+/// instances can be deployed to.
+#[derive(serde::Deserialize, Clone)]
+pub struct HostConfig {
+    pub name: String,
+    /// Anything `DOCKER_HOST` accepts, e.g. `ssh://user@worker-1`.
+    pub endpoint: String,
+}
+
+/// What happens when a watched image has a new version.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdatePolicy {
+    /// Redeploy right away.
+    #[default]
+    Auto,
+    /// Wait until the update is approved with `dispenser approve`.
+    Manual,
+}
+
+/// What happens to the services when dispenser itself stops.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnShutdown {
+    /// Leave the containers running.
+    Detach,
+    /// Stop the containers but keep them around.
+    Stop,
+    /// Stop and remove the containers with `docker compose down`.
+    #[default]
+    Remove,
+}
+
+/// How long pulls and manifest lookups may take
+/// and how often they are retried.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct PullConfig {
+    /// Seconds before a pull or lookup is killed.
+    pub timeout: NonZeroU64,
+    /// Attempts after the first one fails.
+    pub retries: u32,
+    /// Seconds before the first retry, doubled after each one.
+    pub backoff: u64,
+    /// How many pulls and lookups may run at once.
+    pub concurrency: NonZeroUsize,
+}
+
+impl Default for PullConfig {
+    fn default() -> Self {
+        PullConfig {
+            timeout: NonZeroU64::new(300).expect("Non zero"),
+            retries: 3,
+            backoff: 5,
+            concurrency: NonZeroUsize::new(4).expect("Non zero"),
+        }
+    }
+}
+
+impl PullConfig {
+    pub fn get_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.get())
+    }
+}
+
 impl ContposeConfig {
     pub fn init() -> Self {
         Self::try_init().unwrap()
     }
     pub fn try_init() -> Result<Self, Box<dyn std::error::Error>> {
-        use std::io::Read;
-        let mut config = String::new();
-        std::fs::File::open(&crate::cli::get_cli_args().config)?.read_to_string(&mut config)?;
-        Ok(toml::from_str(&config)?)
-    }
-    pub fn get_instances(&self) -> Instances {
-        let inner = self
-            .instance
+        let path = &crate::cli::get_cli_args().config;
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config at {path:?}: {e}"))?;
+        let config: Self =
+            toml::from_str(&config).map_err(|e| format!("Invalid config at {path:?}: {e}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for instance in &self.instance {
+            if let Some(host) = &instance.host {
+                if !self.host.iter().any(|h| &h.name == host) {
+                    return Err(format!(
+                        "Instance {:?} uses host {host:?} which is not declared",
+                        instance.path
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+    /// The docker endpoint an instance should be deployed to,
+    /// `None` meaning the local daemon.
+    pub fn get_endpoint(&self, instance: &ContposeInstanceConfig) -> Option<String> {
+        let name = instance.host.as_ref()?;
+        self.host
             .iter()
-            .cloned()
-            .map(Instance::new)
-            .map(Arc::new)
-            .collect();
+            .find(|host| &host.name == name)
+            .map(|host| host.endpoint.clone())
+    }
+    pub fn get_instances(&self) -> Result<Instances, String> {
+        let pulls = Arc::new(Pulls::new(self.pull));
+        Ok(self.prepare_instances(pulls, |_| None)?.start())
+    }
+    /// Looks up the images of the instances `changes` adds or
+    /// changes, carrying the other running ones in `current` over.
+    /// Nothing is started or stopped yet, and new pull settings
+    /// only apply once the instances start.
+    pub fn prepare_update(
+        &self,
+        current: &Instances,
+        changes: &[PlannedChange],
+    ) -> Result<PreparedInstances, String> {
+        self.prepare_instances(Arc::clone(&current.pulls), |instance| {
+            changes
+                .iter()
+                .any(|change| change.instance == instance.path && change.action == Action::Keep)
+                .then(|| current.find(&instance.path).cloned())
+                .flatten()
+        })
+    }
+    fn prepare_instances(
+        &self,
+        pulls: Arc<Pulls>,
+        reuse: impl Fn(&ContposeInstanceConfig) -> Option<Arc<Instance>> + Sync,
+    ) -> Result<PreparedInstances, String> {
+        crate::registry::login(&self.registry);
+        // Instances are set up in parallel so their initial
+        // lookups and pulls share the concurrency limit
+        let inner = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .instance
+                .iter()
+                .map(|instance| {
+                    let pulls = Arc::clone(&pulls);
+                    let reuse = &reuse;
+                    scope.spawn(move || {
+                        if let Some(existing) = reuse(instance) {
+                            return Ok(PreparedInstance::Running(existing));
+                        }
+                        let mut config = instance.clone();
+                        config.apply_mirrors(&self.mirrors);
+                        Instance::prepare(config, self.get_endpoint(instance), pulls)
+                            .map(PreparedInstance::New)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err("Setting up an instance panicked".to_string()))
+                })
+                .collect::<Result<Vec<_>, String>>()
+        })?;
         let delay = std::time::Duration::from_secs(self.delay.get());
-        Instances { inner, delay }
+        Ok(PreparedInstances {
+            inner,
+            delay,
+            update_policy: self.update_policy,
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout),
+            on_shutdown: self.on_shutdown,
+            pull: self.pull,
+            pulls,
+        })
     }
 }
 
@@ -39,24 +274,178 @@ impl ContposeConfig {
 pub struct ContposeInstanceConfig {
     pub path: PathBuf,
     pub interval: Option<u64>,
+    /// Name of the host this instance is deployed to.
+    pub host: Option<String>,
     images: Vec<Image>,
 }
 
-#[derive(serde::Deserialize, Clone)]
-struct Image {
+#[derive(serde::Deserialize, Clone, PartialEq)]
+pub struct Image {
     registry: String,
     name: String,
     tag: String,
+    /// Refuse new versions that aren't signed accordingly.
+    verify_signature: Option<SignaturePolicy>,
+    /// Whether `registry` is a mirror, which compose
+    /// has to pull from as well.
+    #[serde(skip)]
+    mirrored: bool,
+}
+
+/// Who has to have signed an image for it to be deployed.
+#[derive(serde::Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SignaturePolicy {
+    /// Signed with the private half of a cosign key pair.
+    Key { key: PathBuf },
+    /// Signed keyless by this identity through this OIDC issuer.
+    Keyless { identity: String, issuer: String },
+}
+
+impl Image {
+    /// Whether a webhook's push of `name:tag` is this image. The
+    /// registry isn't compared since mirrored images are watched
+    /// on the mirror but pushed to the original registry.
+    pub fn is_pushed(&self, name: &str, tag: &str) -> bool {
+        self.tag == tag && same_name(&self.name, name)
+    }
+}
+
+/// Splits an image reference as written in a compose file, e.g.
+/// `ghcr.io/acme/app:1.2`, into its name without the registry
+/// and its tag.
+pub fn split_reference(reference: &str) -> (&str, &str) {
+    let reference = reference.split('@').next().unwrap_or(reference);
+    let (name, tag) = match reference.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, tag),
+        _ => (reference, "latest"),
+    };
+    // Only hosts have a dot or a port in them
+    let name = match name.split_once('/') {
+        Some((host, rest)) if host.contains(['.', ':']) || host == "localhost" => rest,
+        _ => name,
+    };
+    (name, tag)
+}
+
+/// Whether two image names without their registry are the
+/// same, Docker Hub's implied `library/` aside.
+pub fn same_name(a: &str, b: &str) -> bool {
+    let unprefixed = |name: &str| name.strip_prefix("library/").unwrap_or(name).to_string();
+    unprefixed(a) == unprefixed(b)
+}
+
+impl std::fmt::Display for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.name, self.tag)
+    }
 }
 
 impl ContposeInstanceConfig {
     pub fn get_interval(&self) -> Duration {
         std::time::Duration::from_secs(self.interval.unwrap_or(5))
     }
-    pub fn get_watchers(&self) -> Vec<DockerWatcher> {
+    pub fn images(&self) -> &[Image] {
+        &self.images
+    }
+    /// Points images at their registry's mirror, if it has one.
+    pub fn apply_mirrors(&mut self, mirrors: &HashMap<String, String>) {
+        for image in &mut self.images {
+            if let Some(mirror) = mirrors.get(&image.registry) {
+                // Docker only implies `library/` for Docker Hub,
+                // a mirror has to be asked for it explicitly
+                let docker_hub = matches!(
+                    image.registry.as_str(),
+                    "docker.io" | "index.docker.io" | "registry-1.docker.io"
+                );
+                if docker_hub && !image.name.contains('/') {
+                    image.name = format!("library/{}", image.name);
+                }
+                image.registry.clone_from(mirror);
+                image.mirrored = true;
+            }
+        }
+    }
+    pub fn get_watchers(
+        &self,
+        pulls: Arc<Pulls>,
+        rejections: Arc<Rejections>,
+    ) -> Result<Vec<DockerWatcher>, String> {
         self.images
             .iter()
-            .map(|image| DockerWatcher::initialize(&image.registry, &image.name, &image.tag))
+            .map(|image| {
+                DockerWatcher::initialize(
+                    &image.registry,
+                    &image.name,
+                    &image.tag,
+                    image.verify_signature.clone(),
+                    image.mirrored,
+                    Arc::clone(&rejections),
+                    Arc::clone(&pulls),
+                )
+            })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(images: &str) -> ContposeInstanceConfig {
+        toml::from_str(&format!("path = \"app\"\nimages = {images}")).expect("Valid instance")
+    }
+
+    #[test]
+    fn mirrors_prefix_docker_hub_official_images() {
+        let mut instance = instance(
+            r#"[
+                { registry = "docker.io", name = "nginx", tag = "latest" },
+                { registry = "docker.io", name = "acme/app", tag = "1.2" },
+                { registry = "ghcr.io", name = "acme/worker", tag = "1.2" },
+            ]"#,
+        );
+        let mirrors =
+            HashMap::from([("docker.io".to_string(), "mirror.internal:5000".to_string())]);
+        instance.apply_mirrors(&mirrors);
+        let images: Vec<_> = instance.images().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            images,
+            [
+                "mirror.internal:5000/library/nginx:latest",
+                "mirror.internal:5000/acme/app:1.2",
+                "ghcr.io/acme/worker:1.2",
+            ]
+        );
+        assert!(instance.images()[0].mirrored);
+        assert!(!instance.images()[2].mirrored);
+    }
+
+    #[test]
+    fn pushes_match_regardless_of_library_prefix() {
+        let mut instance =
+            instance(r#"[{ registry = "docker.io", name = "nginx", tag = "latest" }]"#);
+        assert!(instance.images()[0].is_pushed("library/nginx", "latest"));
+        assert!(!instance.images()[0].is_pushed("library/nginx", "1.27"));
+        let mirrors =
+            HashMap::from([("docker.io".to_string(), "mirror.internal:5000".to_string())]);
+        instance.apply_mirrors(&mirrors);
+        assert!(instance.images()[0].is_pushed("nginx", "latest"));
+        assert!(!instance.images()[0].is_pushed("acme/nginx", "latest"));
+    }
+
+    #[test]
+    fn references_split_into_name_and_tag() {
+        assert_eq!(split_reference("nginx"), ("nginx", "latest"));
+        assert_eq!(split_reference("ghcr.io/acme/app:1.2"), ("acme/app", "1.2"));
+        assert_eq!(
+            split_reference("mirror.internal:5000/library/nginx"),
+            ("library/nginx", "latest")
+        );
+        assert_eq!(split_reference("localhost/app:dev"), ("app", "dev"));
+        assert_eq!(
+            split_reference("acme/app:1.2@sha256:abc"),
+            ("acme/app", "1.2")
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+/// How many events we keep around before
+/// dropping the oldest ones.
+const CAPACITY: usize = 256;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Deploy,
+    Update,
+    Trigger,
+    Reload,
+    Stop,
+    Error,
+    Security,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Event {
+    pub timestamp: String,
+    pub kind: EventKind,
+    pub message: String,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = format!("{:?}", self.kind).to_lowercase();
+        write!(f, "{} {kind:<8} {}", self.timestamp, self.message)
+    }
+}
+
+static EVENTS: OnceLock<Mutex<VecDeque<Event>>> = OnceLock::new();
+
+fn events() -> &'static Mutex<VecDeque<Event>> {
+    EVENTS.get_or_init(|| Mutex::new(VecDeque::with_capacity(CAPACITY)))
+}
+
+/// Keeps track of something that just happened so
+/// operators can inspect it later.
+pub fn record(kind: EventKind, message: impl Into<String>) {
+    let event = Event {
+        timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        kind,
+        message: message.into(),
+    };
+    let mut events = events().lock().expect("Unable to lock events");
+    if events.len() == CAPACITY {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// How many events are currently kept.
+pub fn buffered() -> usize {
+    events().lock().expect("Unable to lock events").len()
+}
+
+/// Returns the last `count` events (or all of them), oldest first.
+pub fn recent(count: Option<usize>) -> Vec<Event> {
+    let events = events().lock().expect("Unable to lock events");
+    let skip = count.map_or(0, |count| events.len().saturating_sub(count));
+    events.iter().skip(skip).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_events_are_evicted() {
+        for i in 0..CAPACITY + 10 {
+            record(EventKind::Update, format!("event {i}"));
+        }
+        assert_eq!(buffered(), CAPACITY);
+        let all = recent(None);
+        assert_eq!(all.len(), CAPACITY);
+        assert_eq!(all[0].message, "event 10");
+        let last: Vec<_> = recent(Some(2)).into_iter().map(|e| e.message).collect();
+        assert_eq!(
+            last,
+            [
+                format!("event {}", CAPACITY + 8),
+                format!("event {}", CAPACITY + 9)
+            ]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::ContposeConfig;
+use crate::master;
+use serde_json::Value;
+use std::fmt::Write;
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// An instance with what its compose project runs.
+struct InstanceGraph {
+    path: String,
+    images: Vec<String>,
+    services: Vec<ServiceGraph>,
+}
+
+struct ServiceGraph {
+    name: String,
+    /// Published ports, e.g. `8080->80/tcp`.
+    ports: Vec<String>,
+    depends_on: Vec<String>,
+}
+
+/// Renders which images trigger a redeploy of which instance,
+/// the services each instance runs, what they depend on and
+/// which ports they publish, so the topology can be documented.
+pub fn render(config: &ContposeConfig, format: GraphFormat) -> String {
+    let instances: Vec<_> = config
+        .instance
+        .iter()
+        .map(|instance| {
+            let path = instance.path.display().to_string();
+            let endpoint = config.get_endpoint(instance);
+            let services = match master::compose_config(
+                &instance.path,
+                endpoint.as_deref(),
+                config.pull.get_timeout(),
+            ) {
+                Ok(project) => services(&project),
+                Err(e) => {
+                    log::warn!("Unable to read the compose project at {path:?}: {e}");
+                    Vec::new()
+                }
+            };
+            InstanceGraph {
+                path,
+                images: instance.images().iter().map(ToString::to_string).collect(),
+                services,
+            }
+        })
+        .collect();
+    match format {
+        GraphFormat::Dot => render_dot(&instances),
+        GraphFormat::Mermaid => render_mermaid(&instances),
+    }
+}
+
+fn services(project: &Value) -> Vec<ServiceGraph> {
+    project
+        .get("services")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(name, service)| ServiceGraph {
+            name: name.clone(),
+            ports: service
+                .get("ports")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(published_port)
+                .collect(),
+            depends_on: service
+                .get("depends_on")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+                .map(|(dependency, _)| dependency.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+fn published_port(port: &Value) -> Option<String> {
+    let published = match port.get("published")? {
+        Value::String(published) if !published.is_empty() => published.clone(),
+        Value::Number(published) => published.to_string(),
+        _ => return None,
+    };
+    let target = port.get("target").and_then(Value::as_u64)?;
+    let protocol = port
+        .get("protocol")
+        .and_then(Value::as_str)
+        .unwrap_or("tcp");
+    Some(match port.get("host_ip").and_then(Value::as_str) {
+        Some(host_ip) if !host_ip.is_empty() => {
+            format!("{host_ip}:{published}->{target}/{protocol}")
+        }
+        _ => format!("{published}->{target}/{protocol}"),
+    })
+}
+
+fn render_dot(instances: &[InstanceGraph]) -> String {
+    let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+    let quote = |text: &str| format!("\"{}\"", escape(text));
+    let mut out = String::from("digraph dispenser {\n    rankdir=LR;\n");
+    for instance in instances {
+        let path = quote(&instance.path);
+        let _ = writeln!(out, "    {path} [shape=box];");
+        for image in &instance.images {
+            let _ = writeln!(out, "    {} -> {path};", quote(image));
+        }
+        let service_id = |name: &str| quote(&format!("{}/{name}", instance.path));
+        for service in &instance.services {
+            let id = service_id(&service.name);
+            // Escaped line by line, `\n` being DOT's line break
+            let label = std::iter::once(&service.name)
+                .chain(&service.ports)
+                .map(|line| escape(line))
+                .collect::<Vec<_>>()
+                .join("\\n");
+            let _ = writeln!(out, "    {id} [label=\"{label}\"];");
+            let _ = writeln!(out, "    {path} -> {id} [arrowhead=none];");
+            for dependency in &service.depends_on {
+                let _ = writeln!(
+                    out,
+                    "    {id} -> {} [style=dashed, label=\"depends on\"];",
+                    service_id(dependency)
+                );
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn render_mermaid(instances: &[InstanceGraph]) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('"', "#quot;"));
+    let mut out = String::from("flowchart LR\n");
+    for (i, instance) in instances.iter().enumerate() {
+        let _ = writeln!(out, "    instance{i}[{}]", quote(&instance.path));
+        for (j, image) in instance.images.iter().enumerate() {
+            let _ = writeln!(out, "    image{i}_{j}([{}]) --> instance{i}", quote(image));
+        }
+        let service_id = |name: &str| {
+            instance
+                .services
+                .iter()
+                .position(|service| service.name == name)
+                .map(|k| format!("service{i}_{k}"))
+        };
+        for (k, service) in instance.services.iter().enumerate() {
+            let label = std::iter::once(service.name.as_str())
+                .chain(service.ports.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join("<br/>");
+            let _ = writeln!(out, "    instance{i} --- service{i}_{k}[{}]", quote(&label));
+            for dependency in &service.depends_on {
+                if let Some(dependency) = service_id(dependency) {
+                    let _ = writeln!(out, "    service{i}_{k} -.->|depends on| {dependency}");
+                }
+            }
+        }
+    }
+    out
+}
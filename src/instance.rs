@@ -1,46 +1,268 @@
-use crate::config::ContposeInstanceConfig;
+use crate::config::{ContposeInstanceConfig, OnShutdown, PullConfig, UpdatePolicy};
+use crate::events::{self, EventKind};
 use crate::manifests::{DockerWatcher, DockerWatcherStatus};
 use crate::master::{DockerComposeMaster, MasterMsg};
-use std::sync::Arc;
+use crate::process::Pulls;
+use crate::signature::Rejections;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 #[derive(Clone)]
 pub struct Instances {
     pub inner: Vec<Arc<Instance>>,
     pub delay: std::time::Duration,
+    pub update_policy: UpdatePolicy,
+    pub shutdown_timeout: std::time::Duration,
+    pub on_shutdown: OnShutdown,
+    /// Shared by every instance to limit concurrent pulls.
+    pub pulls: Arc<Pulls>,
+}
+
+impl Instances {
+    /// Looks up an instance by the path it was configured with.
+    pub fn find(&self, path: &Path) -> Option<&Arc<Instance>> {
+        self.inner.iter().find(|inst| inst.config.path == path)
+    }
+}
+
+/// A snapshot of an instance for `dispenser status`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct InstanceStatus {
+    pub path: PathBuf,
+    pub host: Option<String>,
+    pub state: String,
+    pub last_deploy: Option<SystemTime>,
+    pub pending_update: bool,
+    pub images: Vec<ImageStatus>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ImageStatus {
+    pub reference: String,
+    /// The newest digest found in the registry, which isn't deployed
+    /// yet if it is waiting for approval or failed its signature check.
+    pub latest_digest: String,
+}
+
+/// A container of an instance for `dispenser ps`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ContainerStatus {
+    pub instance: PathBuf,
+    pub service: String,
+    pub name: String,
+    pub image: String,
+    pub state: String,
+    pub health: String,
+    pub status: String,
+    pub ports: Vec<String>,
 }
 
 #[derive(Clone)]
 pub struct Instance {
     pub master: Arc<DockerComposeMaster>,
     watchers: Vec<DockerWatcher>,
+    pending_update: Arc<AtomicBool>,
+    /// Held while polling, since webhooks can ask for a poll
+    /// while the poll loop is already at it.
+    polling: Arc<Mutex<()>>,
     pub config: ContposeInstanceConfig,
 }
 
-impl Instance {
-    pub fn new(config: ContposeInstanceConfig) -> Self {
+/// Instances whose images have all been looked up, ready
+/// to be started once nothing can fail anymore.
+pub struct PreparedInstances {
+    pub inner: Vec<PreparedInstance>,
+    pub delay: std::time::Duration,
+    pub update_policy: UpdatePolicy,
+    pub shutdown_timeout: std::time::Duration,
+    pub on_shutdown: OnShutdown,
+    /// Applied to `pulls` once the instances start.
+    pub pull: PullConfig,
+    pub pulls: Arc<Pulls>,
+}
+
+pub enum PreparedInstance {
+    /// Carried over from the running instances as it is.
+    Running(Arc<Instance>),
+    New(NewInstance),
+}
+
+impl PreparedInstances {
+    pub fn start(self) -> Instances {
+        self.pulls.set_config(self.pull);
+        Instances {
+            inner: self
+                .inner
+                .into_iter()
+                .map(|prepared| match prepared {
+                    PreparedInstance::Running(instance) => instance,
+                    PreparedInstance::New(instance) => Arc::new(instance.start()),
+                })
+                .collect(),
+            delay: self.delay,
+            update_policy: self.update_policy,
+            shutdown_timeout: self.shutdown_timeout,
+            on_shutdown: self.on_shutdown,
+            pulls: self.pulls,
+        }
+    }
+}
+
+/// An instance whose images have been looked up but
+/// whose services aren't being managed yet.
+pub struct NewInstance {
+    config: ContposeInstanceConfig,
+    docker_host: Option<String>,
+    pulls: Arc<Pulls>,
+    watchers: Vec<DockerWatcher>,
+    rejections: Arc<Rejections>,
+}
+
+impl NewInstance {
+    pub fn start(self) -> Instance {
         // Create a docker-compose master.
         // This represents a process that manages
         // when docker compose is lifted or destroyed
-        let master = Arc::new(DockerComposeMaster::initialize(&config.path));
-        let watchers = config.get_watchers();
-        Self {
+        let master = Arc::new(DockerComposeMaster::initialize(
+            &self.config.path,
+            self.docker_host,
+            self.pulls,
+            self.rejections,
+            self.watchers.clone(),
+        ));
+        Instance {
             master,
+            config: self.config,
+            watchers: self.watchers,
+            pending_update: Arc::new(AtomicBool::new(false)),
+            polling: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+impl Instance {
+    /// Looks up the images of an instance without touching
+    /// its services, failing if any of them can't be found.
+    pub fn prepare(
+        config: ContposeInstanceConfig,
+        docker_host: Option<String>,
+        pulls: Arc<Pulls>,
+    ) -> Result<NewInstance, String> {
+        let rejections = Arc::new(Rejections::default());
+        let watchers = config.get_watchers(Arc::clone(&pulls), Arc::clone(&rejections))?;
+        Ok(NewInstance {
             config,
+            docker_host,
+            pulls,
             watchers,
+            rejections,
+        })
+    }
+    pub fn status(&self) -> InstanceStatus {
+        InstanceStatus {
+            path: self.config.path.clone(),
+            host: self.config.host.clone(),
+            state: self.master.state().to_string(),
+            last_deploy: self.master.last_deploy(),
+            pending_update: self.has_pending_update(),
+            images: self
+                .watchers
+                .iter()
+                .map(|watcher| ImageStatus {
+                    reference: watcher.reference(),
+                    latest_digest: watcher.last_digest().to_string(),
+                })
+                .collect(),
         }
     }
-    pub fn poll(&self) {
-        // try to update the watchers and check
-        // if any of them were updated
-        let any_updated = self
-            .watchers
+    pub fn containers(&self) -> Result<Vec<ContainerStatus>, String> {
+        let containers = self.master.ps()?;
+        Ok(containers
+            .into_iter()
+            .map(|container| ContainerStatus {
+                instance: self.config.path.clone(),
+                service: container.service,
+                name: container.name,
+                image: container.image,
+                state: container.state,
+                health: container.health,
+                status: container.status,
+                ports: container
+                    .publishers
+                    .unwrap_or_default()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            })
+            .collect())
+    }
+    pub fn has_pending_update(&self) -> bool {
+        self.pending_update.load(Ordering::SeqCst)
+    }
+    /// Redeploys the instance, which also takes care
+    /// of any update waiting for approval.
+    pub fn deploy(&self) {
+        self.pending_update.store(false, Ordering::SeqCst);
+        self.master.send_msg(MasterMsg::Update);
+    }
+    /// Recreates every container of the instance, whether
+    /// or not there is a new image.
+    pub fn force_deploy(&self, pull: bool) {
+        self.pending_update.store(false, Ordering::SeqCst);
+        self.master.send_msg(MasterMsg::Deploy { pull });
+    }
+    /// Deploys the pending update if there is one and its
+    /// tags still point at the digests that were found.
+    pub fn approve(&self) -> Result<(), String> {
+        if !self.has_pending_update() {
+            return Err(format!(
+                "There is no pending update for {:?}",
+                self.config.path
+            ));
+        }
+        // A push after the update was found hasn't been
+        // looked at by anyone, so it isn't what's approved
+        for watcher in &self.watchers {
+            if watcher.has_moved()? {
+                return Err(format!(
+                    "{} changed since the update was found, approve again after the next poll",
+                    watcher.reference()
+                ));
+            }
+        }
+        if self.pending_update.swap(false, Ordering::SeqCst) {
+            self.master.send_msg(MasterMsg::Update);
+        }
+        Ok(())
+    }
+    pub fn poll(&self, update_policy: UpdatePolicy) {
+        // A second poll right after the first finds nothing new,
+        // rather than both finding the same update
+        let _polling = self.polling.lock().expect("Unable to lock");
+        // Every watcher is updated, not just up to the first new
+        // version, so a rejected image is known before deploying
+        let statuses: Vec<_> = self.watchers.iter().map(|img| img.update()).collect();
+        let any_updated = statuses
             .iter()
-            .any(|img| matches!(img.update(), DockerWatcherStatus::Updated));
+            .any(|status| matches!(status, DockerWatcherStatus::Updated));
 
         // If any of the watchers were updated then we
         // send a message to the master to update
         if any_updated {
-            self.master.send_msg(MasterMsg::Update);
+            match update_policy {
+                UpdatePolicy::Auto => self.master.send_msg(MasterMsg::Update),
+                UpdatePolicy::Manual => {
+                    let path = &self.config.path;
+                    log::info!("Update for {path:?} is waiting for approval");
+                    events::record(
+                        EventKind::Update,
+                        format!("Update for {path:?} is waiting for approval"),
+                    );
+                    self.pending_update.store(true, Ordering::SeqCst);
+                }
+            }
         }
     }
 }
@@ -1,26 +1,74 @@
 use config::ContposeConfig;
 use std::sync::{Arc, Mutex};
+mod admin;
+mod check;
 mod cli;
+mod commands;
 mod config;
+mod events;
+mod graph;
 mod instance;
 mod manifests;
 mod master;
+mod metrics;
+mod plan;
+mod process;
+mod registry;
+mod rootless;
 mod signals;
+mod signature;
+mod webhook;
 
 fn main() {
     // Initialize the loggr
     env_logger::init();
+    rootless::init();
+
+    match &cli::get_cli_args().command {
+        None if cli::get_cli_args().signal.is_some() => {
+            if let Err(e) = commands::signal() {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        None | Some(cli::Command::Run) => (),
+        Some(command) => {
+            if let Err(e) = commands::run(command) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
 
     let config = ContposeConfig::init();
-    let instances = Arc::new(Mutex::new(config.get_instances()));
+    let instances = match config.get_instances() {
+        Ok(instances) => Arc::new(Mutex::new(instances)),
+        Err(e) => {
+            log::error!("Unable to set up the instances: {e}");
+            std::process::exit(1);
+        }
+    };
     signals::handle_reload(instances.clone());
     signals::handle_sigint(instances.clone());
+    signals::handle_dump(instances.clone());
+    signals::handle_poll();
+    admin::serve(&cli::get_cli_args().socket, instances.clone());
+    if let Some(address) = config.metrics {
+        metrics::serve(address, instances.clone());
+    }
+    if let Some(webhook) = &config.webhook {
+        webhook::serve(webhook, instances.clone());
+    }
 
     loop {
         let instances = instances.lock().expect("Poisoned mutex").clone();
-        std::thread::sleep(instances.delay);
+        signals::wait_for_poll(instances.delay);
+        let started = std::time::Instant::now();
         for instance in instances.inner {
-            instance.poll();
+            instance.poll(instances.update_policy);
         }
+        metrics::observe_poll(started.elapsed());
     }
 }
@@ -1,4 +1,9 @@
-use std::io::Write;
+use crate::config::{self, SignaturePolicy};
+use crate::events::{self, EventKind};
+use crate::metrics;
+use crate::process::{self, Pulls};
+use crate::registry;
+use crate::signature::{self, Rejections};
 use std::sync::{Arc, Mutex};
 
 #[derive(serde::Deserialize)]
@@ -61,67 +66,207 @@ pub struct Sha256 {
     pub inner: [u8; 64],
 }
 
+impl std::fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sha256:{}", String::from_utf8_lossy(&self.inner))
+    }
+}
+
 #[derive(Clone)]
 pub struct DockerWatcher {
     registry: Box<str>,
     image: Box<str>,
     tag: Box<str>,
     last_digest: Arc<Mutex<Sha256>>,
+    signature: Option<SignaturePolicy>,
+    /// Whether `registry` is a mirror of the one
+    /// named in the compose file.
+    mirrored: bool,
+    /// The digest the tag pointed at when its signature was
+    /// last verified, which is what gets deployed.
+    verified: Arc<Mutex<Option<String>>>,
+    rejections: Arc<Rejections>,
+    pulls: Arc<Pulls>,
 }
 
 #[derive(Debug, Copy, Clone)]
 pub enum DockerWatcherStatus {
     NotUpdated,
     Updated,
+    /// There is a new version but it failed signature verification.
+    Rejected,
     Deleted,
 }
 
 impl DockerWatcher {
-    pub fn initialize(registry: &str, image: &str, tag: &str) -> Self {
+    pub fn initialize(
+        registry: &str,
+        image: &str,
+        tag: &str,
+        signature: Option<SignaturePolicy>,
+        mirrored: bool,
+        rejections: Arc<Rejections>,
+        pulls: Arc<Pulls>,
+    ) -> Result<Self, String> {
         log::info!("Initializing watch for {registry}/{image}:{tag}");
-        let last_digest = Arc::new(Mutex::new(
-            get_latest_digest(registry, image, tag).expect("There is no initial image digest"),
-        ));
+        let last_digest = get_latest_digest(registry, image, tag, &pulls).ok_or_else(|| {
+            format!("There is no initial image digest for {registry}/{image}:{tag}")
+        })?;
 
-        let registry = registry.into();
-        let image = image.into();
-        let tag = tag.into();
-        DockerWatcher {
-            registry,
-            image,
-            last_digest,
-            tag,
+        let watcher = DockerWatcher {
+            registry: registry.into(),
+            image: image.into(),
+            last_digest: Arc::new(Mutex::new(last_digest)),
+            tag: tag.into(),
+            signature,
+            mirrored,
+            verified: Arc::new(Mutex::new(None)),
+            rejections,
+            pulls,
+        };
+        // What gets deployed on startup has to be signed as well
+        watcher.verify();
+        Ok(watcher)
+    }
+    /// Checks the signature of what the tag points at, recording it
+    /// as rejected if it fails so the instance won't be deployed.
+    fn verify(&self) -> bool {
+        let Some(policy) = &self.signature else {
+            return true;
+        };
+        let reference = self.reference();
+        let pull = self.pulls.config();
+        // The digest, not the tag, is verified and deployed, so
+        // a push in the meantime can't take the verified one's place
+        let verified = signature::resolve_digest(&reference, &pull).and_then(|digest| {
+            let pinned = format!("{}/{}@{digest}", self.registry, self.image);
+            signature::verify(&pinned, policy, &pull).map(|()| digest)
+        });
+        let mut last_verified = self.verified.lock().expect("Unable to lock mutex");
+        match verified {
+            Ok(digest) => {
+                self.rejections.clear(&reference);
+                *last_verified = Some(digest);
+                true
+            }
+            Err(e) => {
+                log::error!("Refusing to deploy {reference}, signature check failed: {e}");
+                events::record(
+                    EventKind::Security,
+                    format!("Refused {reference}, signature check failed: {e}"),
+                );
+                self.rejections.reject(&reference, e);
+                *last_verified = None;
+                false
+            }
         }
     }
+    /// The digest to deploy instead of the tag, for images
+    /// whose signature has to be verified.
+    pub fn verified_digest(&self) -> Option<String> {
+        self.verified.lock().expect("Unable to lock mutex").clone()
+    }
+    /// Whether an image reference from a compose file is the image
+    /// this watches. Registries aren't compared, since mirrored
+    /// images are watched on the mirror.
+    pub fn watches(&self, reference: &str) -> bool {
+        let (name, tag) = config::split_reference(reference);
+        *self.tag == *tag && config::same_name(&self.image, name)
+    }
+    /// Where compose should pull the image from instead of
+    /// the registry in the compose file, if anywhere.
+    pub fn mirror_reference(&self) -> Option<String> {
+        self.mirrored.then(|| self.reference())
+    }
+    pub fn registry(&self) -> &str {
+        &self.registry
+    }
+    pub fn reference(&self) -> String {
+        format!("{}/{}:{}", self.registry, self.image, self.tag)
+    }
+    pub fn last_digest(&self) -> Sha256 {
+        *self.last_digest.lock().expect("Unable to lock mutex")
+    }
+    /// Whether the tag points at something other than the
+    /// digest we last saw.
+    pub fn has_moved(&self) -> Result<bool, String> {
+        let current = get_latest_digest(&self.registry, &self.image, &self.tag, &self.pulls)
+            .ok_or_else(|| format!("Unable to look up {}", self.reference()))?;
+        Ok(current != self.last_digest())
+    }
     pub fn update(&self) -> DockerWatcherStatus {
+        if registry::is_throttled(&self.registry) {
+            log::debug!("Skipping poll of {}, registry is throttled", self.image);
+            return DockerWatcherStatus::NotUpdated;
+        }
         let last_digest = *self.last_digest.lock().expect("Unable to lock mutex");
-        let new_sha256 = get_latest_digest(&self.registry, &self.image, &self.tag);
+        let new_sha256 = get_latest_digest(&self.registry, &self.image, &self.tag, &self.pulls);
         match new_sha256 {
             None => DockerWatcherStatus::Deleted,
             Some(new_sha256) if last_digest == new_sha256 => DockerWatcherStatus::NotUpdated,
             Some(new_sha256) => {
-                let mut last_digest = self.last_digest.lock().expect("Unable to lock mutex");
-                *last_digest = new_sha256;
+                *self.last_digest.lock().expect("Unable to lock mutex") = new_sha256;
+                if !self.verify() {
+                    return DockerWatcherStatus::Rejected;
+                }
                 log::info!(
                     "Found a new version for {}:{}, update will start soon...",
                     self.image,
                     self.tag
                 );
+                events::record(
+                    EventKind::Update,
+                    format!(
+                        "New version of {}/{}:{}",
+                        self.registry, self.image, self.tag
+                    ),
+                );
+                metrics::UPDATES.inc();
                 DockerWatcherStatus::Updated
             }
         }
     }
 }
 
-fn get_latest_digest(registry: &str, image: &str, tag: &str) -> Option<Sha256> {
-    let output_result = std::process::Command::new("docker")
-        .args(["manifest", "inspect"])
-        .arg(format!("{registry}/{image}:{tag}"))
-        .output();
+fn get_latest_digest(registry: &str, image: &str, tag: &str, pulls: &Pulls) -> Option<Sha256> {
+    let reference = format!("{registry}/{image}:{tag}");
+    let pull = pulls.config();
+    let output_result = process::retry(&pull, &format!("Manifest lookup for {reference}"), || {
+        let _permit = pulls.acquire();
+        let output = process::output_with_timeout(
+            std::process::Command::new("docker")
+                .args(["manifest", "inspect"])
+                .arg(&reference),
+            pull.get_timeout(),
+        )
+        .map_err(|e| e.to_string())?;
+        if output.status.success() {
+            return Ok(Ok(output.stdout));
+        }
+        let error = process::last_line(&output.stderr);
+        // Retrying right away only makes rate limits worse,
+        // the whole registry is backed off instead
+        if registry::is_rate_limit(&error) {
+            Ok(Err(error))
+        } else {
+            Err(error)
+        }
+    })
+    .and_then(|output| output);
+    match &output_result {
+        Ok(_) => registry::clear_throttle(registry),
+        Err(e) if registry::is_rate_limit(e) => registry::throttle(registry),
+        Err(_) => (),
+    }
     let val: DockerManifestsResponse = match output_result {
-        Ok(manifest_output) => serde_json::from_slice(&manifest_output.stdout).ok()?,
+        Ok(stdout) => serde_json::from_slice(&stdout).ok()?,
         Err(e) => {
             log::error!("Unable to get manifest for {registry}/{image}:{tag}: {e}");
+            metrics::LOOKUP_FAILURES.inc();
+            events::record(
+                EventKind::Error,
+                format!("Unable to get manifest for {registry}/{image}:{tag}: {e}"),
+            );
             return None;
         }
     };
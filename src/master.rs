@@ -1,12 +1,21 @@
+use crate::events::{self, EventKind};
+use crate::manifests::DockerWatcher;
+use crate::metrics;
+use crate::process::{self, Pulls};
+use crate::registry;
+use crate::signature::Rejections;
+use serde_json::{json, Map, Value};
 use std::{
-    path::Path,
+    collections::HashMap,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{
         atomic::{AtomicU32, Ordering},
-        mpsc::Sender,
-        Arc,
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
     },
     thread::JoinHandle,
+    time::{Duration, SystemTime},
 };
 
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -31,6 +40,13 @@ impl MasterStatus {
     fn into_u32(self) -> u32 {
         self as u32
     }
+    fn as_str(self) -> &'static str {
+        match self {
+            MasterStatus::Stopped => "stopped",
+            MasterStatus::Reloading => "reloading",
+            MasterStatus::Started => "started",
+        }
+    }
 }
 
 struct AtomicMasterStatus(AtomicU32);
@@ -49,8 +65,13 @@ impl AtomicMasterStatus {
 
 pub struct DockerComposeMaster {
     update_msg: Sender<MasterMsg>,
+    /// Receives once the thread is done with `Stop` or `Detach`.
+    stopped: Mutex<Receiver<()>>,
     watcher_thread: Option<JoinHandle<()>>,
     status: Arc<AtomicMasterStatus>,
+    last_deploy: Arc<Mutex<Option<SystemTime>>>,
+    path: Box<Path>,
+    docker_host: Option<String>,
 }
 
 impl Drop for DockerComposeMaster {
@@ -60,52 +81,169 @@ impl Drop for DockerComposeMaster {
     }
 }
 
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ComposeContainer {
+    pub name: String,
+    pub service: String,
+    pub image: String,
+    pub state: String,
+    #[serde(default)]
+    pub health: String,
+    /// Human readable status, e.g. `Up 2 hours (healthy)`.
+    pub status: String,
+    #[serde(default)]
+    pub publishers: Option<Vec<ComposePublisher>>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ComposePublisher {
+    #[serde(rename = "URL", default)]
+    pub url: String,
+    pub target_port: u16,
+    pub published_port: u16,
+    pub protocol: String,
+}
+
+impl std::fmt::Display for ComposePublisher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.published_port == 0 {
+            return write!(f, "{}/{}", self.target_port, self.protocol);
+        }
+        write!(
+            f,
+            "{}:{}->{}/{}",
+            self.url, self.published_port, self.target_port, self.protocol
+        )
+    }
+}
+
 pub enum MasterMsg {
     Detach,
     Update,
-    Stop,
+    /// Recreate every container, pulling first if asked to,
+    /// even if nothing changed.
+    Deploy {
+        pull: bool,
+    },
+    /// Take the services down, giving them `timeout` to exit,
+    /// and bring them up again.
+    Restart {
+        timeout: Duration,
+    },
+    /// Stop the services, removing their containers if asked to,
+    /// giving them `timeout` to exit before they are killed.
+    Stop {
+        timeout: Duration,
+        remove: bool,
+    },
 }
 
 impl DockerComposeMaster {
-    pub fn is_stopped(&self) -> bool {
-        self.status.load(Ordering::SeqCst) == MasterStatus::Stopped
+    /// Blocks until the thread has handled a `Stop` or `Detach`.
+    /// A master whose services never came up is also `Stopped`,
+    /// so the status can't tell whether it is done.
+    pub fn wait_stopped(&self) {
+        let _ = self.stopped.lock().expect("Unable to lock").recv();
     }
     pub fn is_started(&self) -> bool {
         self.status.load(Ordering::SeqCst) == MasterStatus::Started
     }
+    pub fn state(&self) -> &'static str {
+        self.status.load(Ordering::SeqCst).as_str()
+    }
+    /// When docker compose last came up successfully.
+    pub fn last_deploy(&self) -> Option<SystemTime> {
+        *self.last_deploy.lock().expect("Unable to lock")
+    }
+    /// The docker endpoint this master deploys to,
+    /// `None` meaning the local daemon.
+    pub fn docker_host(&self) -> Option<&str> {
+        self.docker_host.as_deref()
+    }
+    /// A `docker compose` command for this master's project,
+    /// for one-off commands outside of the deploy loop.
+    pub fn compose_command(&self) -> Command {
+        compose(&self.path, self.docker_host.as_deref())
+    }
+    /// The containers of this master's project as
+    /// reported by `docker compose ps`.
+    pub fn ps(&self) -> Result<Vec<ComposeContainer>, String> {
+        let output = self
+            .compose_command()
+            .args(["ps", "--all", "--format", "json"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(process::last_line(&output.stderr));
+        }
+        // Older compose versions print a JSON array, newer
+        // ones a JSON object per line
+        if let Ok(containers) = serde_json::from_slice(&output.stdout) {
+            return Ok(containers);
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+            .collect()
+    }
     pub fn send_msg(&self, msg: MasterMsg) {
         let _ = self.update_msg.send(msg);
     }
-    pub fn initialize(path: impl AsRef<Path>) -> Self {
+    pub fn initialize(
+        path: impl AsRef<Path>,
+        docker_host: Option<String>,
+        pulls: Arc<Pulls>,
+        rejections: Arc<Rejections>,
+        watchers: Vec<DockerWatcher>,
+    ) -> Self {
         let status_shared = Arc::new(AtomicMasterStatus::new(MasterStatus::Stopped));
         let status = Arc::clone(&status_shared);
+        let last_deploy_shared = Arc::new(Mutex::new(None));
+        let last_deploy = Arc::clone(&last_deploy_shared);
         let (update_msg, update_recv) = std::sync::mpsc::channel::<MasterMsg>();
+        let (stopped_send, stopped) = std::sync::mpsc::channel::<()>();
         let path: Box<Path> = path.as_ref().into();
         let watch_fn = {
             let path = path.clone();
+            let docker_host = docker_host.clone();
+            let mut up = ComposeUp::default();
             move || loop {
-                let exit_status = Command::new("docker")
-                    .arg("compose")
-                    .arg("up")
-                    .args(["--pull", "always"])
-                    .arg("-d")
-                    .current_dir(&path)
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status();
-                match exit_status {
-                    Ok(es) if es.success() => {
-                        log::info!("Services for {path:?} are up and running!");
-                        status_shared.store(MasterStatus::Started, Ordering::SeqCst);
-                    }
-                    Ok(es) => log::warn!(
-                        "Docker compose up at {path:?} not successful exit with code {:?}",
-                        es.code()
-                    ),
-                    Err(e) => {
-                        log::error!("Failed to invoce docker compose at {path:?}: {}", e);
-                        std::process::exit(1);
+                // Whatever asked for the deploy, a version that failed
+                // its signature check must not be pulled and started
+                if let Some(rejected) = rejections.outstanding() {
+                    log::error!("Refusing to deploy {path:?}, {rejected}");
+                    events::record(
+                        EventKind::Security,
+                        format!("Refused to deploy {path:?}, {rejected}"),
+                    );
+                } else {
+                    match compose_up(&path, docker_host.as_deref(), up, &pulls, &watchers) {
+                        Ok(deploy_id) => {
+                            metrics::DEPLOYS.inc();
+                            *last_deploy_shared.lock().expect("Unable to lock") =
+                                Some(SystemTime::now());
+                            log::info!(
+                                "Services for {path:?} are up and running! (deploy {deploy_id})"
+                            );
+                            events::record(
+                                EventKind::Deploy,
+                                format!("Services for {path:?} are up (deploy {deploy_id})"),
+                            );
+                            status_shared.store(MasterStatus::Started, Ordering::SeqCst);
+                        }
+                        Err(e) => {
+                            log::warn!("Docker compose up at {path:?} not successful: {e}");
+                            metrics::DEPLOY_FAILURES.inc();
+                            events::record(
+                                EventKind::Error,
+                                format!("Docker compose up at {path:?} failed: {e}"),
+                            );
+                        }
                     }
                 }
 
@@ -113,24 +251,49 @@ impl DockerComposeMaster {
                 match update_recv.recv().expect("Broken pipe") {
                     MasterMsg::Update => {
                         log::info!("Received update directive. Composing the updated services at {path:?}...");
+                        up = ComposeUp::default();
+                    }
+                    MasterMsg::Deploy { pull } => {
+                        log::info!(
+                            "Received deploy directive. Recreating the services at {path:?}..."
+                        );
+                        up = ComposeUp {
+                            pull,
+                            force_recreate: true,
+                        };
                     }
-                    MasterMsg::Stop => {
+                    MasterMsg::Restart { timeout } => {
+                        log::info!("Received restart directive for {path:?}");
+                        // Taking the services down is pointless if
+                        // they can't be brought up again
+                        if rejections.outstanding().is_none() {
+                            let _ = compose(&path, docker_host.as_deref())
+                                .args(["down", "--timeout", &timeout.as_secs().to_string()])
+                                .status();
+                        }
+                        // Only the images already on the host are used, so a
+                        // restart doesn't deploy updates, approved or not
+                        up = ComposeUp {
+                            pull: false,
+                            force_recreate: false,
+                        };
+                    }
+                    MasterMsg::Stop { timeout, remove } => {
                         log::warn!("Received stop signal for instace {path:?}");
-                        let _ = Command::new("docker")
-                            .arg("compose")
-                            .arg("down")
-                            .current_dir(&path)
-                            .stdin(Stdio::null())
-                            .stdout(Stdio::null())
-                            .stderr(Stdio::null())
+                        let _ = compose(&path, docker_host.as_deref())
+                            .arg(if remove { "down" } else { "stop" })
+                            .args(["--timeout", &timeout.as_secs().to_string()])
                             .status();
                         log::warn!("Stopped the compose service at {path:?}");
+                        events::record(EventKind::Stop, format!("Stopped services for {path:?}"));
                         status_shared.store(MasterStatus::Stopped, Ordering::SeqCst);
+                        let _ = stopped_send.send(());
                         break;
                     }
                     MasterMsg::Detach => {
                         log::warn!("Detaching from docker compose at {path:?}");
                         status_shared.store(MasterStatus::Stopped, Ordering::SeqCst);
+                        let _ = stopped_send.send(());
                         break;
                     }
                 }
@@ -140,7 +303,319 @@ impl DockerComposeMaster {
         DockerComposeMaster {
             watcher_thread,
             update_msg,
+            stopped: Mutex::new(stopped),
             status,
+            last_deploy,
+            path,
+            docker_host,
+        }
+    }
+}
+
+/// How `docker compose up` should treat images and containers.
+#[derive(Clone, Copy)]
+struct ComposeUp {
+    /// Pull every image instead of only missing ones.
+    pull: bool,
+    force_recreate: bool,
+}
+
+impl Default for ComposeUp {
+    fn default() -> Self {
+        ComposeUp {
+            pull: true,
+            force_recreate: false,
+        }
+    }
+}
+
+/// Pulls and starts the services at `path`, retrying
+/// failed or timed out attempts. Returns the deploy id
+/// that was handed to the containers.
+fn compose_up(
+    path: &Path,
+    docker_host: Option<&str>,
+    up: ComposeUp,
+    pulls: &Pulls,
+    watchers: &[DockerWatcher],
+) -> Result<String, String> {
+    let pull = pulls.config();
+    let deploy_id = format!(
+        "{:x}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    );
+    let mut rate_limited = None;
+    let result = process::retry(&pull, &format!("Docker compose up at {path:?}"), || {
+        let metadata = write_metadata(
+            path,
+            docker_host,
+            pull.get_timeout(),
+            watchers,
+            &deploy_id,
+            up.force_recreate,
+        )?;
+        let _permit = pulls.acquire();
+        let mut command = compose(path, docker_host);
+        for file in compose_files(path) {
+            command.arg("--file").arg(file);
+        }
+        command
+            .arg("--file")
+            .arg(metadata)
+            .arg("up")
+            .args(["--pull", if up.pull { "always" } else { "missing" }])
+            .arg("-d");
+        if up.force_recreate {
+            command.arg("--force-recreate");
         }
+        let output = process::output_with_timeout(&mut command, pull.get_timeout());
+        match output {
+            Ok(output) if output.status.success() => Ok(Ok(deploy_id.clone())),
+            Ok(output) => {
+                let error = format!(
+                    "exit code {:?}: {}",
+                    output.status.code(),
+                    process::last_line(&output.stderr)
+                );
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                // Retrying right away only makes rate limits worse,
+                // the registry is backed off instead
+                if registry::is_rate_limit(&stderr) {
+                    rate_limited = Some(stderr.into_owned());
+                    Ok(Err(error))
+                } else {
+                    Err(error)
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Err(e.to_string()),
+            Err(e) => {
+                log::error!("Failed to invoce docker compose at {path:?}: {}", e);
+                std::process::exit(1);
+            }
+        }
+    })
+    .and_then(|result| result);
+    if let (Err(_), Some(stderr)) = (&result, rate_limited) {
+        for registry in rate_limited_registries(&stderr, watchers) {
+            registry::throttle(&registry);
+        }
+    }
+    result
+}
+
+/// The registries of `watchers` a rate limit error from a pull
+/// names, or all of them if it doesn't name any, since Docker
+/// Hub's message doesn't.
+fn rate_limited_registries(stderr: &str, watchers: &[DockerWatcher]) -> Vec<String> {
+    let mut registries: Vec<String> = watchers
+        .iter()
+        .map(|watcher| watcher.registry().to_string())
+        .collect();
+    registries.sort();
+    registries.dedup();
+    let named: Vec<String> = registries
+        .iter()
+        .filter(|registry| stderr.contains(registry.as_str()))
+        .cloned()
+        .collect();
+    if named.is_empty() {
+        registries
+    } else {
+        named
+    }
+}
+
+/// Written next to the compose file of every instance.
+const METADATA_FILE: &str = ".dispenser-metadata.json";
+
+/// The files `docker compose` picks up by itself in `path`, which
+/// have to be named once another one is passed with `--file`.
+fn compose_files(path: &Path) -> Vec<PathBuf> {
+    let first = |names: [&str; 4]| {
+        names
+            .into_iter()
+            .map(|name| path.join(name))
+            .find(|file| file.exists())
+    };
+    first([
+        "compose.yaml",
+        "compose.yml",
+        "docker-compose.yaml",
+        "docker-compose.yml",
+    ])
+    .into_iter()
+    .chain(first([
+        "compose.override.yaml",
+        "compose.override.yml",
+        "docker-compose.override.yaml",
+        "docker-compose.override.yml",
+    ]))
+    .collect()
+}
+
+/// Writes a compose file adding dispenser's metadata to the
+/// environment of every service, pulling mirrored images from
+/// their mirror and pinning signed ones to their verified
+/// digest. Returns where it is.
+///
+/// A service whose image didn't change keeps the deploy id and
+/// dispenser version it was deployed with, so restarting or
+/// upgrading dispenser doesn't recreate it.
+fn write_metadata(
+    path: &Path,
+    docker_host: Option<&str>,
+    timeout: Duration,
+    watchers: &[DockerWatcher],
+    deploy_id: &str,
+    force_recreate: bool,
+) -> Result<PathBuf, String> {
+    let project = compose_config(path, docker_host, timeout)?;
+    let deployed = if force_recreate {
+        HashMap::new()
+    } else {
+        deployed_metadata(path, docker_host, timeout)
+    };
+    let mut services = Map::new();
+    let project_services = project
+        .get("services")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten();
+    for (name, service) in project_services {
+        let image = service.get("image").and_then(Value::as_str);
+        let watcher =
+            image.and_then(|image| watchers.iter().find(|watcher| watcher.watches(image)));
+        let image_sha = watcher.map(|watcher| watcher.last_digest().to_string());
+        let mut environment = Map::new();
+        environment.insert("DISPENSER_SERVICE_NAME".into(), json!(name));
+        environment.insert("DISPENSER_INSTANCE".into(), json!(path));
+        environment.insert("DISPENSER_VERSION".into(), json!(env!("CARGO_PKG_VERSION")));
+        environment.insert("DISPENSER_DEPLOY_ID".into(), json!(deploy_id));
+        if let Some(image_sha) = &image_sha {
+            environment.insert("DISPENSER_IMAGE_SHA".into(), json!(image_sha));
+        }
+        let previous = deployed.get(name);
+        if previous.and_then(|env| env.get("DISPENSER_IMAGE_SHA")) == image_sha.as_ref() {
+            for key in ["DISPENSER_VERSION", "DISPENSER_DEPLOY_ID"] {
+                if let Some(value) = previous.and_then(|env| env.get(key)) {
+                    environment.insert(key.into(), json!(value));
+                }
+            }
+        }
+        let mut overrides = Map::new();
+        overrides.insert("environment".into(), json!(environment));
+        // Mirrored images are pulled from the mirror they are watched
+        // on, and signed ones by the digest that was verified, since
+        // the tag could point at something else by now
+        if let (Some(image), Some(watcher)) = (image, watcher) {
+            let mirror = watcher.mirror_reference();
+            let digest = watcher.verified_digest();
+            if mirror.is_some() || digest.is_some() {
+                let image =
+                    mirror.unwrap_or_else(|| image.split('@').next().unwrap_or(image).to_string());
+                let pinned = match digest {
+                    Some(digest) => format!("{image}@{digest}"),
+                    None => image,
+                };
+                overrides.insert("image".into(), json!(pinned));
+            }
+        }
+        services.insert(name.clone(), Value::Object(overrides));
+    }
+
+    let file = path.join(METADATA_FILE);
+    let contents = json!({ "services": services }).to_string();
+    std::fs::write(&file, contents).map_err(|e| format!("Unable to write {file:?}: {e}"))?;
+    Ok(file)
+}
+
+/// The `DISPENSER_*` variables the existing containers of each
+/// service were deployed with.
+fn deployed_metadata(
+    path: &Path,
+    docker_host: Option<&str>,
+    timeout: Duration,
+) -> HashMap<String, HashMap<String, String>> {
+    let mut deployed = HashMap::new();
+    let Ok(output) = process::output_with_timeout(
+        compose(path, docker_host).args(["ps", "--all", "--quiet"]),
+        timeout,
+    ) else {
+        return deployed;
+    };
+    let ids = String::from_utf8_lossy(&output.stdout);
+    let ids: Vec<_> = ids.split_whitespace().collect();
+    if ids.is_empty() {
+        return deployed;
+    }
+    let mut inspect = Command::new("docker");
+    inspect.arg("inspect").args(&ids);
+    if let Some(docker_host) = docker_host {
+        inspect.env("DOCKER_HOST", docker_host);
+    }
+    let Ok(output) = process::output_with_timeout(&mut inspect, timeout) else {
+        return deployed;
+    };
+    let containers: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    for container in containers {
+        let Some(service) = container
+            .pointer("/Config/Labels/com.docker.compose.service")
+            .and_then(Value::as_str)
+        else {
+            continue;
+        };
+        let env = container
+            .pointer("/Config/Env")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .filter_map(|var| var.split_once('='))
+            .filter(|(key, _)| key.starts_with("DISPENSER_"))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        deployed.insert(service.to_string(), env);
+    }
+    deployed
+}
+
+/// The compose project at `path` as normalized by `docker compose config`.
+pub fn compose_config(
+    path: &Path,
+    docker_host: Option<&str>,
+    timeout: Duration,
+) -> Result<Value, String> {
+    let output = process::output_with_timeout(
+        compose(path, docker_host).args(["config", "--format", "json"]),
+        timeout,
+    )
+    .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(process::last_line(&output.stderr));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())
+}
+
+/// Prepares a `docker compose` invocation for the project
+/// at `path`, talking to `docker_host` if one is given.
+///
+/// Some metadata about the deployment is exported so compose
+/// files can also use it, e.g. in labels.
+pub fn compose(path: &Path, docker_host: Option<&str>) -> Command {
+    let mut command = Command::new("docker");
+    command
+        .arg("compose")
+        .env("DISPENSER_VERSION", env!("CARGO_PKG_VERSION"))
+        .env("DISPENSER_INSTANCE", path)
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some(docker_host) = docker_host {
+        command.env("DOCKER_HOST", docker_host);
     }
+    command
 }
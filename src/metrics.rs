@@ -0,0 +1,150 @@
+use crate::instance::Instances;
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static POLLS: Counter = Counter::new();
+/// Total time spent polling, in milliseconds.
+static POLL_MILLIS: Counter = Counter::new();
+pub static UPDATES: Counter = Counter::new();
+pub static DEPLOYS: Counter = Counter::new();
+pub static DEPLOY_FAILURES: Counter = Counter::new();
+pub static LOOKUP_FAILURES: Counter = Counter::new();
+
+pub fn observe_poll(duration: Duration) {
+    POLLS.inc();
+    POLL_MILLIS.add(duration.as_millis() as u64);
+}
+
+/// Serves the metrics in the Prometheus text format on
+/// `address`, so existing monitoring can scrape dispenser.
+pub fn serve(address: SocketAddr, instances: Arc<Mutex<Instances>>) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to bind metrics endpoint at {address}: {e}");
+            return;
+        }
+    };
+    log::info!("Serving metrics at http://{address}/metrics");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_client(stream, &instances));
+            if let Err(e) = result {
+                log::debug!("Metrics request failed: {e}");
+            }
+        }
+    });
+}
+
+fn handle_client(mut stream: TcpStream, instances: &Mutex<Instances>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers, we don't need any of them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = if request_line.starts_with("GET /metrics ") {
+        let instances = instances.lock().expect("Unable to lock").clone();
+        ("200 OK", render(&instances))
+    } else {
+        ("404 Not Found", String::from("Not found\n"))
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn render(instances: &Instances) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    };
+    metric(
+        "dispenser_instances",
+        "gauge",
+        "Instances currently managed.",
+        instances.inner.len().to_string(),
+    );
+    metric(
+        "dispenser_pending_updates",
+        "gauge",
+        "Instances with an update waiting for approval.",
+        instances
+            .inner
+            .iter()
+            .filter(|inst| inst.has_pending_update())
+            .count()
+            .to_string(),
+    );
+    metric(
+        "dispenser_polls_total",
+        "counter",
+        "Rounds of polling all instances for new images.",
+        POLLS.get().to_string(),
+    );
+    metric(
+        "dispenser_poll_duration_seconds_total",
+        "counter",
+        "Time spent polling for new images.",
+        format!("{:.3}", POLL_MILLIS.get() as f64 / 1000.0),
+    );
+    metric(
+        "dispenser_updates_total",
+        "counter",
+        "New image versions found.",
+        UPDATES.get().to_string(),
+    );
+    metric(
+        "dispenser_deploys_total",
+        "counter",
+        "Successful docker compose deploys.",
+        DEPLOYS.get().to_string(),
+    );
+    metric(
+        "dispenser_deploy_failures_total",
+        "counter",
+        "Docker compose deploys that failed after all retries.",
+        DEPLOY_FAILURES.get().to_string(),
+    );
+    metric(
+        "dispenser_manifest_failures_total",
+        "counter",
+        "Manifest lookups that failed after all retries.",
+        LOOKUP_FAILURES.get().to_string(),
+    );
+    out
+}
@@ -0,0 +1,215 @@
+use crate::config::{ContposeConfig, ContposeInstanceConfig};
+use crate::instance::Instances;
+use std::path::PathBuf;
+
+/// What a reload would do to an instance.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// New in the config, its services are brought up.
+    Add,
+    /// Gone from the config, its services are taken down.
+    Remove,
+    /// Its images or host changed, it is set up again.
+    Change,
+    /// Nothing changed, it keeps running untouched.
+    Keep,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Action::Add => "+",
+            Action::Remove => "-",
+            Action::Change => "~",
+            Action::Keep => " ",
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PlannedChange {
+    pub instance: PathBuf,
+    pub action: Action,
+    /// What changed, for instances that are set up again.
+    pub reasons: Vec<String>,
+}
+
+impl std::fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.action, self.instance.display())?;
+        if !self.reasons.is_empty() {
+            write!(f, " ({})", self.reasons.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares a freshly read config with the running
+/// instances without touching either of them.
+pub fn plan(current: &Instances, new_config: &ContposeConfig) -> Vec<PlannedChange> {
+    let running: Vec<_> = current
+        .inner
+        .iter()
+        .map(|inst| (&inst.config, inst.master.docker_host()))
+        .collect();
+    diff(&running, new_config)
+}
+
+/// Compares the config and docker host of every
+/// running instance with the new config.
+fn diff(
+    running: &[(&ContposeInstanceConfig, Option<&str>)],
+    new_config: &ContposeConfig,
+) -> Vec<PlannedChange> {
+    let mut changes: Vec<PlannedChange> = running
+        .iter()
+        .filter(|(config, _)| {
+            !new_config
+                .instance
+                .iter()
+                .any(|new| new.path == config.path)
+        })
+        .map(|(config, _)| PlannedChange {
+            instance: config.path.clone(),
+            action: Action::Remove,
+            reasons: Vec::new(),
+        })
+        .collect();
+
+    for new in &new_config.instance {
+        let Some((config, docker_host)) =
+            running.iter().find(|(config, _)| config.path == new.path)
+        else {
+            changes.push(PlannedChange {
+                instance: new.path.clone(),
+                action: Action::Add,
+                reasons: Vec::new(),
+            });
+            continue;
+        };
+        // Running instances already have their mirrors applied
+        let mut new_instance = new.clone();
+        new_instance.apply_mirrors(&new_config.mirrors);
+        let mut reasons = Vec::new();
+        if new_instance.images() != config.images() {
+            reasons.push("images changed".to_string());
+        }
+        if new_config.get_endpoint(new).as_deref() != *docker_host {
+            reasons.push("host changed".to_string());
+        }
+        let action = if reasons.is_empty() {
+            Action::Keep
+        } else {
+            Action::Change
+        };
+        changes.push(PlannedChange {
+            instance: new.path.clone(),
+            action,
+            reasons,
+        });
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> ContposeConfig {
+        toml::from_str(toml).expect("Valid config")
+    }
+
+    const RUNNING: &str = r#"
+        delay = 60
+        mirrors = { "docker.io" = "mirror.internal:5000" }
+
+        [[host]]
+        name = "worker"
+        endpoint = "ssh://deploy@worker-1"
+
+        [[instance]]
+        path = "kept"
+        images = [{ registry = "docker.io", name = "nginx", tag = "latest" }]
+
+        [[instance]]
+        path = "retagged"
+        images = [{ registry = "ghcr.io", name = "acme/app", tag = "1.0" }]
+
+        [[instance]]
+        path = "moved"
+        images = [{ registry = "ghcr.io", name = "acme/app", tag = "1.0" }]
+
+        [[instance]]
+        path = "removed"
+        images = [{ registry = "ghcr.io", name = "acme/app", tag = "1.0" }]
+    "#;
+
+    #[test]
+    fn diff_keeps_changes_adds_and_removes() {
+        let current = config(RUNNING);
+        let mut running_configs = current.instance.clone();
+        for instance in &mut running_configs {
+            instance.apply_mirrors(&current.mirrors);
+        }
+        let hosts: Vec<_> = running_configs
+            .iter()
+            .map(|instance| current.get_endpoint(instance))
+            .collect();
+        let running: Vec<_> = running_configs
+            .iter()
+            .zip(&hosts)
+            .map(|(instance, host)| (instance, host.as_deref()))
+            .collect();
+
+        let new = config(
+            r#"
+            delay = 60
+            mirrors = { "docker.io" = "mirror.internal:5000" }
+
+            [[host]]
+            name = "worker"
+            endpoint = "ssh://deploy@worker-1"
+
+            [[instance]]
+            path = "kept"
+            images = [{ registry = "docker.io", name = "nginx", tag = "latest" }]
+
+            [[instance]]
+            path = "retagged"
+            images = [{ registry = "ghcr.io", name = "acme/app", tag = "2.0" }]
+
+            [[instance]]
+            path = "moved"
+            host = "worker"
+            images = [{ registry = "ghcr.io", name = "acme/app", tag = "1.0" }]
+
+            [[instance]]
+            path = "added"
+            images = [{ registry = "ghcr.io", name = "acme/app", tag = "1.0" }]
+            "#,
+        );
+
+        let changes = diff(&running, &new);
+        let action = |path: &str| {
+            changes
+                .iter()
+                .find(|change| change.instance == std::path::Path::new(path))
+                .map(|change| (change.action, change.reasons.clone()))
+        };
+        // Mirrors applied to both sides don't count as a change
+        assert_eq!(action("kept"), Some((Action::Keep, vec![])));
+        assert_eq!(
+            action("retagged"),
+            Some((Action::Change, vec!["images changed".to_string()]))
+        );
+        assert_eq!(
+            action("moved"),
+            Some((Action::Change, vec!["host changed".to_string()]))
+        );
+        assert_eq!(action("removed"), Some((Action::Remove, vec![])));
+        assert_eq!(action("added"), Some((Action::Add, vec![])));
+        assert_eq!(changes.len(), 5);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::PullConfig;
+use std::{
+    fmt::Display,
+    io::{self, Read},
+    os::unix::process::CommandExt,
+    process::{Command, Output, Stdio},
+    sync::{Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Runs `command` to completion and collects its output,
+/// killing it and everything it started if it takes longer
+/// than `timeout`.
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    // Its own process group, so the compose plugin the docker
+    // CLI starts can be killed along with it
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    // Drain the pipes while we wait so a chatty
+    // process can't block on a full buffer
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            kill_group(child.id());
+            let _ = child.wait();
+            // The pipes close once the whole group is gone
+            let _ = stdout.map(join_output);
+            let _ = stderr.map(join_output);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out after {timeout:?}"),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.map(join_output).unwrap_or_default(),
+        stderr: stderr.map(join_output).unwrap_or_default(),
+    })
+}
+
+fn kill_group(pgid: u32) {
+    // A negative pid stands for the whole process group
+    let result = Command::new("kill")
+        .args(["-KILL", "--", &format!("-{pgid}")])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if let Err(e) = result {
+        log::error!("Unable to kill process group {pgid}: {e}");
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+fn join_output(handle: JoinHandle<Vec<u8>>) -> Vec<u8> {
+    handle.join().unwrap_or_default()
+}
+
+/// The last line a process wrote to stderr, which is
+/// usually the one explaining why it failed.
+pub fn last_line(stderr: &[u8]) -> String {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+/// Runs `attempt` until it succeeds, backing off exponentially
+/// between failures as configured in `pull`.
+pub fn retry<T, E: Display>(
+    pull: &PullConfig,
+    what: &str,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = Duration::from_secs(pull.backoff);
+    let mut retries = pull.retries;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) if retries > 0 => {
+                log::warn!("{what} failed: {e}. Retrying in {backoff:?}...");
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries -= 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The pull settings and the concurrency limit they set, shared
+/// by every watcher and master so a reload can change them
+/// without setting the instances up again.
+pub struct Pulls {
+    config: Mutex<PullConfig>,
+    semaphore: Semaphore,
+}
+
+impl Pulls {
+    pub fn new(config: PullConfig) -> Self {
+        Pulls {
+            config: Mutex::new(config),
+            semaphore: Semaphore::new(config.concurrency.get()),
+        }
+    }
+    pub fn config(&self) -> PullConfig {
+        *self.config.lock().expect("Unable to lock")
+    }
+    /// Applies new settings to the pulls that start from now on.
+    pub fn set_config(&self, config: PullConfig) {
+        *self.config.lock().expect("Unable to lock") = config;
+        self.semaphore.resize(config.concurrency.get());
+    }
+    /// Blocks until a pull may start. The permit is
+    /// given back when the returned guard is dropped.
+    pub fn acquire(&self) -> Permit<'_> {
+        self.semaphore.acquire()
+    }
+}
+
+/// Limits how many pulls can run at the same time.
+struct Semaphore {
+    permits: Mutex<Permits>,
+    released: Condvar,
+}
+
+struct Permits {
+    /// Negative while more are taken than the
+    /// limit allows after it was lowered.
+    free: isize,
+    total: usize,
+}
+
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(Permits {
+                free: permits as isize,
+                total: permits,
+            }),
+            released: Condvar::new(),
+        }
+    }
+    fn acquire(&self) -> Permit<'_> {
+        let mut permits = self.permits.lock().expect("Unable to lock permits");
+        while permits.free <= 0 {
+            permits = self.released.wait(permits).expect("Unable to lock permits");
+        }
+        permits.free -= 1;
+        Permit(self)
+    }
+    fn resize(&self, total: usize) {
+        let mut permits = self.permits.lock().expect("Unable to lock permits");
+        permits.free += total as isize - permits.total as isize;
+        permits.total = total;
+        self.released.notify_all();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.permits.lock().expect("Unable to lock permits").free += 1;
+        self.0.released.notify_one();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::RegistryConfig;
+use crate::events::{self, EventKind};
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// First pause after a registry rate limits us,
+/// doubled every time it happens again in a row.
+const THROTTLE_BASE: Duration = Duration::from_secs(60);
+const THROTTLE_MAX: Duration = Duration::from_secs(60 * 60);
+
+struct Throttle {
+    until: Instant,
+    strikes: u32,
+}
+
+static THROTTLES: OnceLock<Mutex<HashMap<String, Throttle>>> = OnceLock::new();
+
+fn throttles() -> &'static Mutex<HashMap<String, Throttle>> {
+    THROTTLES.get_or_init(Default::default)
+}
+
+/// Logs the docker CLI into every registry that has credentials
+/// configured, so pulls and manifest lookups don't depend on
+/// someone having run `docker login` as the dispenser user.
+pub fn login(registries: &[RegistryConfig]) {
+    for registry in registries {
+        if let Err(e) = try_login(registry) {
+            log::error!("Unable to log into {}: {e}", registry.name);
+            events::record(
+                EventKind::Error,
+                format!("Unable to log into {}: {e}", registry.name),
+            );
+        }
+    }
+}
+
+pub fn try_login(registry: &RegistryConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let password = registry.get_password()?;
+    let mut child = Command::new("docker")
+        .arg("login")
+        .arg(&registry.name)
+        .args(["--username", &registry.username])
+        .arg("--password-stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("No stdin for docker login")?
+        .write_all(password.trim_end().as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(crate::process::last_line(&output.stderr).into());
+    }
+    log::info!("Logged into {}", registry.name);
+    Ok(())
+}
+
+/// Whether a docker error means the registry is rate limiting us.
+pub fn is_rate_limit(message: &str) -> bool {
+    message.contains("toomanyrequests") || message.contains("429 Too Many Requests")
+}
+
+/// Stops polling `registry` for a while, backing off
+/// further every time it rate limits us again.
+pub fn throttle(registry: &str) {
+    let mut throttles = throttles().lock().expect("Unable to lock throttles");
+    let throttle = throttles.entry(registry.to_string()).or_insert(Throttle {
+        until: Instant::now(),
+        strikes: 0,
+    });
+    throttle.strikes += 1;
+    let wait = THROTTLE_BASE
+        .saturating_mul(2u32.saturating_pow(throttle.strikes - 1))
+        .min(THROTTLE_MAX);
+    let wait = wait + jitter(wait / 5);
+    throttle.until = Instant::now() + wait;
+    log::warn!("Registry {registry} is rate limiting us, pausing polls for {wait:?}");
+    events::record(
+        EventKind::Error,
+        format!("Registry {registry} is rate limiting us, pausing polls for {wait:?}"),
+    );
+}
+
+/// Whether polls against `registry` are paused.
+pub fn is_throttled(registry: &str) -> bool {
+    let throttles = throttles().lock().expect("Unable to lock throttles");
+    throttles
+        .get(registry)
+        .is_some_and(|throttle| throttle.until > Instant::now())
+}
+
+/// Registries whose polls are paused, with how long
+/// they stay paused.
+pub fn throttled() -> Vec<(String, Duration)> {
+    let now = Instant::now();
+    throttles()
+        .lock()
+        .expect("Unable to lock throttles")
+        .iter()
+        .filter(|(_, throttle)| throttle.until > now)
+        .map(|(registry, throttle)| (registry.clone(), throttle.until - now))
+        .collect()
+}
+
+/// Forgets past rate limits once a request goes through.
+pub fn clear_throttle(registry: &str) {
+    throttles()
+        .lock()
+        .expect("Unable to lock throttles")
+        .remove(registry);
+}
+
+/// A pseudo random duration up to `max` so watchers
+/// don't all hit the registry again at the same time.
+fn jitter(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+    let max_millis = max.as_millis() as u64;
+    if max_millis == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(nanos % max_millis)
+}
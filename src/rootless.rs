@@ -0,0 +1,46 @@
+use std::path::{Path, PathBuf};
+
+const ROOTFUL_SOCKET: &str = "/var/run/docker.sock";
+
+/// Points docker at a rootless docker or podman daemon
+/// when there is no rootful one and `DOCKER_HOST` is unset.
+/// Every docker command we run inherits the variable.
+pub fn init() {
+    if std::env::var_os("DOCKER_HOST").is_some() || Path::new(ROOTFUL_SOCKET).exists() {
+        return;
+    }
+    if let Some(socket) = user_sockets().into_iter().find(|socket| socket.exists()) {
+        log::info!("Using the rootless docker socket at {socket:?}");
+        std::env::set_var("DOCKER_HOST", format!("unix://{}", socket.display()));
+    }
+}
+
+fn user_sockets() -> Vec<PathBuf> {
+    let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") else {
+        return Vec::new();
+    };
+    let runtime_dir = PathBuf::from(runtime_dir);
+    vec![
+        runtime_dir.join("docker.sock"),
+        runtime_dir.join("podman").join("podman.sock"),
+    ]
+}
+
+/// Whether the local docker daemon runs as the current user.
+pub fn is_rootless() -> bool {
+    let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") else {
+        return false;
+    };
+    std::env::var("DOCKER_HOST").is_ok_and(|host| {
+        host.strip_prefix("unix://")
+            .is_some_and(|socket| Path::new(socket).starts_with(&runtime_dir))
+    })
+}
+
+/// The lowest port a rootless daemon may publish.
+pub fn unprivileged_port_start() -> u16 {
+    std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+        .ok()
+        .and_then(|start| start.trim().parse().ok())
+        .unwrap_or(1024)
+}
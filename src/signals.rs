@@ -1,38 +1,100 @@
+use crate::events::{self, EventKind};
 use crate::master::MasterMsg;
-use crate::{config::ContposeConfig, instance::Instances};
+use crate::plan::{self, Action};
+use crate::registry;
+use crate::{
+    config::{ContposeConfig, OnShutdown},
+    instance::{Instance, Instances},
+};
 use signal_hook::{
-    consts::{SIGHUP, SIGINT},
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
     iterator::Signals,
 };
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+
+/// Held for the whole of a reload. SIGHUP and the admin
+/// socket can both ask for one, and two reloads planned from
+/// the same instances would stop and replace them twice.
+static RELOADING: Mutex<()> = Mutex::new(());
+
+/// Set when a poll was asked for before the
+/// delay between polls ran out.
+static POLL_NOW: OnceLock<(Mutex<bool>, Condvar)> = OnceLock::new();
+
+fn poll_now() -> &'static (Mutex<bool>, Condvar) {
+    POLL_NOW.get_or_init(|| (Mutex::new(false), Condvar::new()))
+}
+
+/// Cuts the current wait between polls short.
+pub fn request_poll() {
+    let (requested, wake) = poll_now();
+    *requested.lock().expect("Unable to lock") = true;
+    wake.notify_all();
+}
+
+/// Sleeps for `delay` or until a poll is requested.
+pub fn wait_for_poll(delay: Duration) {
+    let (requested, wake) = poll_now();
+    let requested = requested.lock().expect("Unable to lock");
+    let (mut requested, _) = wake
+        .wait_timeout_while(requested, delay, |requested| !*requested)
+        .expect("Unable to lock");
+    *requested = false;
+}
+
+pub fn handle_poll() {
+    let mut signals = Signals::new([SIGUSR2]).expect("No signals :(");
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            log::info!("Received SIGUSR2, polling right away");
+            request_poll();
+        }
+    });
+}
 
 /// What should we do when the user stops
-/// this program?
+/// this program? systemd stops services with
+/// SIGTERM, Ctrl-C sends SIGINT.
 pub fn handle_sigint(instances: Arc<Mutex<Instances>>) {
-    let mut signals = Signals::new([SIGINT]).expect("No signals :(");
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("No signals :(");
 
     std::thread::spawn(move || {
-        signals.forever().for_each(|_| {
-            let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Stopping]);
-            // Check if there are any paths that were deleted
-            let current_instances = instances.lock().expect("Unable to lock").clone();
+        if signals.forever().next().is_some() {
+            shutdown(&instances);
+        }
+    });
+}
 
-            for curr_instance in &current_instances.inner {
-                curr_instance.master.send_msg(MasterMsg::Stop);
-            }
+/// Stops every instance, last configured first, and
+/// exits once they are all down.
+pub fn shutdown(instances: &Mutex<Instances>) -> ! {
+    let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Stopping]);
+    let current_instances = instances.lock().expect("Unable to lock").clone();
+    let total = current_instances.inner.len();
 
-            // Wait until all current instances are stopped or detached
-            loop {
-                if current_instances
-                    .inner
-                    .iter()
-                    .all(|inst| inst.master.is_stopped())
-                {
-                    std::process::exit(0);
-                }
-            }
-        });
-    });
+    // Instances listed later may well depend on earlier
+    // ones, so they are taken down first and one at a time
+    for (stopped, curr_instance) in current_instances.inner.iter().rev().enumerate() {
+        let path = &curr_instance.config.path;
+        let status = format!("Stopping {path:?} ({}/{total})", stopped + 1);
+        let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Status(&status)]);
+        let msg = match current_instances.on_shutdown {
+            OnShutdown::Detach => MasterMsg::Detach,
+            OnShutdown::Stop => MasterMsg::Stop {
+                timeout: current_instances.shutdown_timeout,
+                remove: false,
+            },
+            OnShutdown::Remove => MasterMsg::Stop {
+                timeout: current_instances.shutdown_timeout,
+                remove: true,
+            },
+        };
+        curr_instance.master.send_msg(msg);
+        curr_instance.master.wait_stopped();
+    }
+    std::process::exit(0);
 }
 
 pub fn handle_reload(instances: Arc<Mutex<Instances>>) {
@@ -40,46 +102,127 @@ pub fn handle_reload(instances: Arc<Mutex<Instances>>) {
 
     std::thread::spawn(move || {
         for _ in signals.forever() {
-            let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Reloading]);
-            // Read the config again
-            let new_config = ContposeConfig::try_init();
-
-            match new_config {
-                Ok(new_config) => {
-                    // Check if there are any paths that were deleted
-                    let current_instances = instances.lock().expect("Unable to lock").clone();
-
-                    for curr_instance in &current_instances.inner {
-                        // Is the new config does not include the current instance we
-                        // send a message to stop
-                        if !new_config
-                            .instance
-                            .iter()
-                            .any(|inst| inst.path == curr_instance.config.path)
-                        {
-                            curr_instance.master.send_msg(MasterMsg::Stop);
-                        } else {
-                            curr_instance.master.send_msg(MasterMsg::Detach);
-                        }
-                    }
-
-                    // Wait until all current instances are stopped or detached
-                    loop {
-                        if current_instances
-                            .inner
-                            .iter()
-                            .all(|inst| inst.master.is_stopped())
-                        {
-                            break;
-                        }
-                    }
-
-                    let mut instances = instances.lock().expect("Unable to lock");
-                    *instances = new_config.get_instances();
-                }
-                Err(err) => log::error!("Unable to read new config: {err}"),
+            let _ = reload(&instances);
+        }
+    });
+}
+
+/// Reads the config again, stops the instances that were
+/// removed from it and sets up the ones that changed.
+pub fn reload(instances: &Mutex<Instances>) -> Result<(), String> {
+    let _reloading = RELOADING.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Reloading]);
+    let result = match try_reload(instances) {
+        Ok(changed) => {
+            log::info!("Reloaded the configuration, {changed} instance(s) changed");
+            events::record(
+                EventKind::Reload,
+                format!("Reloaded the configuration, {changed} instance(s) changed"),
+            );
+            Ok(())
+        }
+        Err(err) => {
+            log::error!("Unable to reload the config: {err}");
+            events::record(
+                EventKind::Error,
+                format!("Unable to reload the config: {err}"),
+            );
+            Err(format!("Unable to reload the config: {err}"))
+        }
+    };
+    let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
+    result
+}
+
+/// The instances lock is only held to read and swap them, since
+/// looking up images can take minutes and polling, `status` and
+/// metrics carry on with the current instances meanwhile.
+fn try_reload(instances: &Mutex<Instances>) -> Result<usize, String> {
+    // Read the config again
+    let new_config = ContposeConfig::try_init().map_err(|e| e.to_string())?;
+    let current_instances = instances.lock().expect("Unable to lock").clone();
+    // Only instances the new config removes or changes are
+    // touched, the others keep running and polling as they are
+    let changes = plan::plan(&current_instances, &new_config);
+    // Look everything up before stopping anything, so a failed
+    // lookup leaves the running instances as they were
+    let prepared = new_config.prepare_update(&current_instances, &changes)?;
+
+    let mut touched = Vec::new();
+    for change in &changes {
+        let Some(curr_instance) = current_instances.find(&change.instance) else {
+            continue;
+        };
+        match change.action {
+            Action::Remove => curr_instance.master.send_msg(MasterMsg::Stop {
+                timeout: current_instances.shutdown_timeout,
+                remove: true,
+            }),
+            // Moving to another host, the services on the old one
+            // would be left behind with nothing managing them
+            Action::Change if moved_host(&new_config, curr_instance) => {
+                curr_instance.master.send_msg(MasterMsg::Stop {
+                    timeout: current_instances.shutdown_timeout,
+                    remove: true,
+                })
             }
-            let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
+            Action::Change => curr_instance.master.send_msg(MasterMsg::Detach),
+            Action::Add | Action::Keep => continue,
+        }
+        touched.push(curr_instance);
+    }
+    // Wait until the touched instances are stopped or detached
+    for inst in &touched {
+        inst.master.wait_stopped();
+    }
+
+    let new_instances = prepared.start();
+    *instances.lock().expect("Unable to lock") = new_instances;
+    Ok(changes
+        .iter()
+        .filter(|change| change.action != Action::Keep)
+        .count())
+}
+
+fn moved_host(new_config: &ContposeConfig, instance: &Instance) -> bool {
+    new_config
+        .instance
+        .iter()
+        .find(|new| new.path == instance.config.path)
+        .is_some_and(|new| new_config.get_endpoint(new).as_deref() != instance.master.docker_host())
+}
+
+/// Logs everything dispenser is keeping track of as JSON,
+/// to see what a stuck daemon is up to.
+pub fn handle_dump(instances: Arc<Mutex<Instances>>) {
+    let mut signals = Signals::new([SIGUSR1]).expect("No signals :(");
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let current_instances = instances.lock().expect("Unable to lock").clone();
+            let throttled: Vec<_> = registry::throttled()
+                .into_iter()
+                .map(|(registry, remaining)| {
+                    serde_json::json!({
+                        "registry": registry,
+                        "remaining_secs": remaining.as_secs(),
+                    })
+                })
+                .collect();
+            let state = serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "pid": std::process::id(),
+                "delay_secs": current_instances.delay.as_secs(),
+                "update_policy": current_instances.update_policy,
+                "instances": current_instances
+                    .inner
+                    .iter()
+                    .map(|inst| inst.status())
+                    .collect::<Vec<_>>(),
+                "throttled_registries": throttled,
+                "buffered_events": events::buffered(),
+            });
+            log::info!("State dump: {state}");
         }
     });
 }
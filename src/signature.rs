@@ -0,0 +1,75 @@
+use crate::config::{PullConfig, SignaturePolicy};
+use crate::process;
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Images of an instance whose current version failed its
+/// signature check, shared by the instance's watchers and its
+/// master so nothing is deployed while any is outstanding.
+#[derive(Default)]
+pub struct Rejections(Mutex<BTreeMap<String, String>>);
+
+impl Rejections {
+    pub fn reject(&self, reference: &str, reason: String) {
+        self.0
+            .lock()
+            .expect("Unable to lock")
+            .insert(reference.to_string(), reason);
+    }
+    pub fn clear(&self, reference: &str) {
+        self.0.lock().expect("Unable to lock").remove(reference);
+    }
+    /// Why the instance can't be deployed right now, if it can't.
+    pub fn outstanding(&self) -> Option<String> {
+        let rejections = self.0.lock().expect("Unable to lock");
+        let (reference, reason) = rejections.iter().next()?;
+        Some(format!("{reference} failed its signature check: {reason}"))
+    }
+}
+
+/// The digest the tag of `reference` points at, as the registry
+/// reports it. For multi-platform images that is the digest of
+/// the index, which is what `cosign sign` signs.
+pub fn resolve_digest(reference: &str, pull: &PullConfig) -> Result<String, String> {
+    let output = process::output_with_timeout(
+        Command::new("docker")
+            .args(["buildx", "imagetools", "inspect", reference])
+            .args(["--format", "{{json .Manifest}}"]),
+        pull.get_timeout(),
+    )
+    .map_err(|e| format!("Unable to run docker buildx: {e}"))?;
+    if !output.status.success() {
+        return Err(process::last_line(&output.stderr));
+    }
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
+    manifest
+        .get("digest")
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| format!("No digest for {reference}"))
+}
+
+/// Checks the cosign signature of `reference` against `policy`.
+pub fn verify(reference: &str, policy: &SignaturePolicy, pull: &PullConfig) -> Result<(), String> {
+    let mut command = Command::new("cosign");
+    command.arg("verify");
+    match policy {
+        SignaturePolicy::Key { key } => {
+            command.arg("--key").arg(key);
+        }
+        SignaturePolicy::Keyless { identity, issuer } => {
+            command
+                .args(["--certificate-identity", identity])
+                .args(["--certificate-oidc-issuer", issuer]);
+        }
+    }
+    let output = process::output_with_timeout(command.arg(reference), pull.get_timeout())
+        .map_err(|e| format!("Unable to run cosign: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(process::last_line(&output.stderr))
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config::WebhookConfig;
+use crate::events::{self, EventKind};
+use crate::instance::Instances;
+use serde_json::Value;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Payloads larger than this aren't push notifications.
+const MAX_BODY: usize = 1024 * 1024;
+/// Limit on the request line and headers together.
+const MAX_HEAD: u64 = 16 * 1024;
+/// How long a client gets to send its whole request.
+const DEADLINE: Duration = Duration::from_secs(10);
+
+/// Listens for push webhooks from registries, so a new version
+/// is picked up right away instead of on the next poll.
+pub fn serve(config: &WebhookConfig, instances: Arc<Mutex<Instances>>) {
+    let secret = match config.get_secret() {
+        Ok(secret) => secret,
+        Err(e) => {
+            log::error!("Unable to read the webhook secret: {e}");
+            return;
+        }
+    };
+    let address = config.address;
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to bind webhook endpoint at {address}: {e}");
+            return;
+        }
+    };
+    log::info!("Listening for registry webhooks at http://{address}/webhook");
+
+    let secret: Arc<str> = secret.into();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Unable to accept webhook connection: {e}");
+                    continue;
+                }
+            };
+            // Each client gets its own thread so a slow
+            // one can't hold up everybody else
+            let secret = Arc::clone(&secret);
+            let instances = Arc::clone(&instances);
+            std::thread::spawn(move || {
+                if let Err(e) = handle_client(stream, &secret, &instances) {
+                    log::debug!("Webhook request failed: {e}");
+                }
+            });
+        }
+    });
+}
+
+/// Reads from a stream until `until`, however slowly the
+/// other end trickles in its bytes.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+fn handle_client(
+    mut stream: TcpStream,
+    secret: &str,
+    instances: &Mutex<Instances>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(Deadline {
+        stream: &stream,
+        until: Instant::now() + DEADLINE,
+    });
+    let mut head = (&mut reader).take(MAX_HEAD);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut header = String::new();
+    while head.read_line(&mut header)? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+        header.clear();
+    }
+    if head.limit() == 0 {
+        return respond(
+            &mut stream,
+            "431 Request Header Fields Too Large",
+            "Headers too large",
+        );
+    }
+
+    let Some(target) = request_line
+        .strip_prefix("POST ")
+        .and_then(|rest| rest.split(' ').next())
+    else {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "Only POST is accepted",
+        );
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/webhook" {
+        return respond(&mut stream, "404 Not Found", "Not found");
+    }
+    // Docker Hub can only put the secret in the URL,
+    // Harbor sends it as the Authorization header
+    let query_secret = query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("secret="))
+        .find_map(|value| urlencoding::decode(value).ok());
+    let header_secret = authorization
+        .as_deref()
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value));
+    let authorized = query_secret.is_some_and(|given| same_secret(&given, secret))
+        || header_secret.is_some_and(|given| same_secret(given, secret));
+    if !authorized {
+        log::warn!(
+            "Refused a webhook from {:?} with a wrong secret",
+            stream.peer_addr()
+        );
+        return respond(&mut stream, "401 Unauthorized", "Wrong secret");
+    }
+    if content_length > MAX_BODY {
+        return respond(&mut stream, "413 Payload Too Large", "Payload too large");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return respond(&mut stream, "400 Bad Request", "Payload is not JSON");
+    };
+
+    let pushed = pushed_images(&payload);
+    let instances = instances.lock().expect("Unable to lock").clone();
+    let matching: Vec<_> = instances
+        .inner
+        .iter()
+        .filter(|inst| {
+            inst.config
+                .images()
+                .iter()
+                .any(|image| pushed.iter().any(|(name, tag)| image.is_pushed(name, tag)))
+        })
+        .cloned()
+        .collect();
+    if matching.is_empty() {
+        log::info!("Received a webhook for {pushed:?}, no instance uses it");
+        return respond(&mut stream, "200 OK", "No instance uses this image");
+    }
+    respond(
+        &mut stream,
+        "202 Accepted",
+        &format!("Checking {} instance(s) for updates", matching.len()),
+    )?;
+
+    // The registry gets its answer before the lookups, which can
+    // take longer than it waits. Going through a poll keeps
+    // signature checks and manual approval in place.
+    let update_policy = instances.update_policy;
+    std::thread::spawn(move || {
+        for instance in matching {
+            let path = &instance.config.path;
+            log::info!("Checking {path:?} for updates after a registry webhook");
+            events::record(EventKind::Trigger, format!("Webhook push for {path:?}"));
+            instance.poll(update_policy);
+        }
+    });
+    Ok(())
+}
+
+/// The `(name, tag)` pairs a Docker Hub, Harbor or
+/// GitHub Container Registry push notification is about.
+fn pushed_images(payload: &Value) -> Vec<(String, String)> {
+    let str_at = |value: &Value, pointer: &str| {
+        value
+            .pointer(pointer)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let mut pushed = Vec::new();
+
+    // Docker Hub
+    if let (Some(name), Some(tag)) = (
+        str_at(payload, "/repository/repo_name"),
+        str_at(payload, "/push_data/tag"),
+    ) {
+        pushed.push((name, tag));
+    }
+
+    // Harbor
+    if let Some(name) = str_at(payload, "/event_data/repository/repo_full_name") {
+        let resources = payload
+            .pointer("/event_data/resources")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten();
+        for resource in resources {
+            if let Some(tag) = str_at(resource, "/tag") {
+                pushed.push((name.clone(), tag));
+            }
+        }
+    }
+
+    // GitHub sends `package` or the older `registry_package` events
+    for package in ["/package", "/registry_package"] {
+        let Some(package) = payload.pointer(package) else {
+            continue;
+        };
+        if let (Some(namespace), Some(name), Some(tag)) = (
+            str_at(package, "/namespace"),
+            str_at(package, "/name"),
+            str_at(package, "/package_version/container_metadata/tag/name"),
+        ) {
+            // GHCR image names are always lowercase
+            pushed.push((format!("{namespace}/{name}").to_lowercase(), tag));
+        }
+    }
+    pushed
+}
+
+/// Compares every byte so the time taken doesn't
+/// tell how much of a guess was right.
+fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+        body.len() + 1
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn docker_hub_push() {
+        let payload = json!({
+            "push_data": { "tag": "1.2", "pusher": "acme" },
+            "repository": { "repo_name": "acme/app", "namespace": "acme" }
+        });
+        assert_eq!(
+            pushed_images(&payload),
+            [("acme/app".to_string(), "1.2".to_string())]
+        );
+    }
+
+    #[test]
+    fn harbor_push_with_several_tags() {
+        let payload = json!({
+            "type": "PUSH_ARTIFACT",
+            "event_data": {
+                "resources": [
+                    { "digest": "sha256:abc", "tag": "1.2" },
+                    { "digest": "sha256:abc", "tag": "latest" }
+                ],
+                "repository": { "name": "app", "repo_full_name": "acme/app" }
+            }
+        });
+        assert_eq!(
+            pushed_images(&payload),
+            [
+                ("acme/app".to_string(), "1.2".to_string()),
+                ("acme/app".to_string(), "latest".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn github_package_events() {
+        let package = json!({
+            "namespace": "Acme",
+            "name": "App",
+            "package_version": {
+                "container_metadata": { "tag": { "name": "1.2" } }
+            }
+        });
+        let expected = [("acme/app".to_string(), "1.2".to_string())];
+        assert_eq!(pushed_images(&json!({ "package": package })), expected);
+        assert_eq!(
+            pushed_images(&json!({ "registry_package": package })),
+            expected
+        );
+    }
+
+    #[test]
+    fn unknown_payload() {
+        assert!(pushed_images(&json!({ "hello": "world" })).is_empty());
+    }
+
+    #[test]
+    fn secrets_compare_whole() {
+        assert!(same_secret("hunter2", "hunter2"));
+        assert!(!same_secret("hunter", "hunter2"));
+        assert!(!same_secret("hunter3", "hunter2"));
+    }
+}